@@ -0,0 +1,117 @@
+// Multi-gets of the same keys over and over, run with
+//
+//     cargo bench --bench multiget -- --keys 100 --value-size 1000
+//
+// The server runs in this process, so the allocations counted per multi-get
+// are the server's: the client reuses its buffers and allocates nothing once
+// it is going. None of them is a copy of a value, values go from the store
+// straight into the reply, which tests/allocations.rs holds the server to.
+
+extern crate remcached;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use remcached::{Address, Config};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// `--name value` or `--name=value`; cargo bench adds a --bench of its own
+fn arg<T: FromStr>(name: &str, default: T) -> T {
+    let args: Vec<String> = env::args().collect();
+    for (i, arg) in args.iter().enumerate() {
+        let value = if arg == name {
+            args.get(i + 1).cloned()
+        } else {
+            arg.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')).map(str::to_string)
+        };
+        if let Some(value) = value {
+            return value.parse().unwrap_or_else(|_| panic!("invalid {} {:?}", name, value));
+        }
+    }
+    default
+}
+
+fn main() {
+    let keys: usize = arg("--keys", 100);
+    let value_size: usize = arg("--value-size", 100);
+    let requests: usize = arg("--requests", 10000);
+
+    let path = env::temp_dir().join(format!("remcached-bench-{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+    let config = Config { bind: Address::Unix(path.clone()), ..Config::default() };
+    thread::spawn(move || remcached::serve(config).expect("server failed"));
+    let started = Instant::now();
+    let mut socket = loop {
+        match UnixStream::connect(&path) {
+            Ok(socket) => break socket,
+            Err(_) if started.elapsed() < Duration::from_secs(5) => thread::sleep(Duration::from_millis(10)),
+            Err(e) => panic!("server never came up on {}: {}", path.display(), e),
+        }
+    };
+
+    let value = vec![b'x'; value_size];
+    let mut reply = [0; 8];
+    for key in 0..keys {
+        let mut command = format!("set key:{} 0 0 {}\r\n", key, value.len()).into_bytes();
+        command.extend(&value);
+        command.extend(b"\r\n");
+        socket.write_all(&command).unwrap();
+        socket.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"STORED\r\n");
+    }
+
+    let names: Vec<String> = (0..keys).map(|key| format!("key:{}", key)).collect();
+    let request = format!("get {}\r\n", names.join(" ")).into_bytes();
+    let expected: usize = names.iter().map(|name| format!("VALUE {} 0 {}\r\n", name, value_size).len() + value_size + 2).sum::<usize>() + b"END\r\n".len();
+    let mut reply = vec![0; expected];
+    // once around to warm up the server's buffers
+    socket.write_all(&request).unwrap();
+    socket.read_exact(&mut reply).unwrap();
+    assert!(reply.ends_with(b"END\r\n"), "unexpected reply");
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let started = Instant::now();
+    for _ in 0..requests {
+        socket.write_all(&request).unwrap();
+        socket.read_exact(&mut reply).unwrap();
+    }
+    let elapsed = started.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!("multi-gets    {} of {} keys with {} byte values", requests, keys, value_size);
+    println!("elapsed       {:.3}s", elapsed.as_secs_f64());
+    println!("throughput    {:.0} multi-gets/s, {:.1} MB/s", requests as f64 / elapsed.as_secs_f64(),
+             (requests * expected) as f64 / elapsed.as_secs_f64() / 1e6);
+    println!("allocations   {:.1} per multi-get", allocations as f64 / requests as f64);
+    let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,225 @@
+// Load generator speaking the text protocol, run with
+//
+//     cargo bench --bench throughput -- --clients 8 --requests 20000
+//
+// Without --server it starts a server of its own in this process on a unix
+// socket. Prints operations per second and latency percentiles, where one
+// operation is a batch of --pipeline commands sent together.
+
+extern crate remcached;
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use remcached::{Address, Config};
+
+struct Options {
+    server: Option<Address>,
+    // server threads when starting our own
+    threads: usize,
+    clients: usize,
+    // batches each client sends
+    requests: usize,
+    pipeline: usize,
+    keys: usize,
+    value_size: usize,
+    // percentage of commands that are sets, the rest are gets
+    sets: u64,
+}
+
+// `--name value` or `--name=value`; cargo bench adds a --bench of its own
+fn arg<T: FromStr>(name: &str, default: T) -> T {
+    let args: Vec<String> = env::args().collect();
+    for (i, arg) in args.iter().enumerate() {
+        let value = if arg == name {
+            args.get(i + 1).cloned()
+        } else {
+            arg.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')).map(str::to_string)
+        };
+        if let Some(value) = value {
+            return value.parse().unwrap_or_else(|_| panic!("invalid {} {:?}", name, value));
+        }
+    }
+    default
+}
+
+fn options() -> Options {
+    Options {
+        server: arg("--server", String::new()).parse().ok(),
+        threads: arg("--threads", 1),
+        clients: arg("--clients", 4),
+        requests: arg("--requests", 10000),
+        pipeline: arg::<usize>("--pipeline", 1).max(1),
+        keys: arg::<usize>("--keys", 1000).max(1),
+        value_size: arg("--value-size", 100),
+        sets: arg("--sets", 10),
+    }
+}
+
+// Small and fast, the keys and the get/set mix only have to be spread out
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+fn connect(addr: &Address) -> io::Result<Box<dyn Stream>> {
+    match *addr {
+        Address::Tcp(ref addr) => {
+            let socket = TcpStream::connect(addr)?;
+            socket.set_nodelay(true)?;
+            Ok(Box::new(socket))
+        }
+        Address::Unix(ref path) => Ok(Box::new(UnixStream::connect(path)?)),
+    }
+}
+
+// Runs a server of our own on a fresh unix socket, it goes away with us
+fn spawn_server(threads: usize) -> Address {
+    let path = env::temp_dir().join(format!("remcached-bench-{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+    let bind = Address::Unix(path.clone());
+    let config = Config { bind: bind.clone(), threads, ..Config::default() };
+    thread::spawn(move || remcached::serve(config).expect("server failed"));
+    wait_for(&path);
+    bind
+}
+
+fn wait_for(path: &PathBuf) {
+    let started = Instant::now();
+    while UnixStream::connect(path).is_err() {
+        if started.elapsed() > Duration::from_secs(5) {
+            panic!("server never came up on {}", path.display());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn line(reader: &mut BufReader<Box<dyn Stream>>) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection"));
+    }
+    Ok(line)
+}
+
+// Reads the reply to one get or set
+fn reply(reader: &mut BufReader<Box<dyn Stream>>, hits: &mut u64) -> io::Result<()> {
+    loop {
+        let header = line(reader)?;
+        if header == "STORED\r\n" || header == "END\r\n" {
+            return Ok(());
+        }
+        let len = match header.strip_prefix("VALUE ").and_then(|rest| rest.split_whitespace().nth(2)) {
+            Some(len) => len.parse::<usize>().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply {:?}", header))),
+        };
+        let mut value = vec![0; len + 2];
+        reader.read_exact(&mut value)?;
+        *hits += 1;
+    }
+}
+
+struct Run {
+    latencies: Vec<Duration>,
+    hits: u64,
+}
+
+fn client(addr: &Address, options: &Options, seed: u64) -> io::Result<Run> {
+    let mut reader = BufReader::new(connect(addr)?);
+    let mut rng = Rng(seed);
+    let value = vec![b'x'; options.value_size];
+    let mut batch = Vec::new();
+    let mut run = Run { latencies: Vec::with_capacity(options.requests), hits: 0 };
+    for _ in 0..options.requests {
+        batch.clear();
+        for _ in 0..options.pipeline {
+            let key = rng.next() % options.keys as u64;
+            if rng.next() % 100 < options.sets {
+                batch.extend(format!("set key:{} 0 0 {}\r\n", key, value.len()).into_bytes());
+                batch.extend(&value);
+                batch.extend(b"\r\n");
+            } else {
+                batch.extend(format!("get key:{}\r\n", key).into_bytes());
+            }
+        }
+        let started = Instant::now();
+        reader.get_mut().write_all(&batch)?;
+        for _ in 0..options.pipeline {
+            reply(&mut reader, &mut run.hits)?;
+        }
+        run.latencies.push(started.elapsed());
+    }
+    Ok(run)
+}
+
+// Every key gets a value first so gets hit
+fn preload(addr: &Address, options: &Options) -> io::Result<()> {
+    let mut reader = BufReader::new(connect(addr)?);
+    let value = vec![b'x'; options.value_size];
+    let mut hits = 0;
+    for key in 0..options.keys {
+        let mut command = format!("set key:{} 0 0 {}\r\n", key, value.len()).into_bytes();
+        command.extend(&value);
+        command.extend(b"\r\n");
+        reader.get_mut().write_all(&command)?;
+        reply(&mut reader, &mut hits)?;
+    }
+    Ok(())
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let index = ((sorted.len() as f64 * p).ceil() as usize).clamp(1, sorted.len()) - 1;
+    sorted[index]
+}
+
+fn main() {
+    let options = options();
+    let addr = match options.server {
+        Some(ref addr) => addr.clone(),
+        None => spawn_server(options.threads),
+    };
+    preload(&addr, &options).expect("failed to preload keys");
+
+    let started = Instant::now();
+    let runs: Vec<Run> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..options.clients).map(|id| {
+            let (addr, options) = (&addr, &options);
+            scope.spawn(move || client(addr, options, 0x9e3779b97f4a7c15 ^ (id as u64 + 1)))
+        }).collect();
+        workers.into_iter().map(|worker| worker.join().unwrap().expect("client failed")).collect()
+    });
+    let elapsed = started.elapsed();
+
+    let mut latencies: Vec<Duration> = runs.iter().flat_map(|run| run.latencies.iter().cloned()).collect();
+    latencies.sort();
+    let commands = latencies.len() * options.pipeline;
+    let hits: u64 = runs.iter().map(|run| run.hits).sum();
+    println!("server        {}", addr);
+    println!("clients       {} x {} batches of {} commands, {}% sets, {} keys of {} bytes",
+             options.clients, options.requests, options.pipeline, options.sets, options.keys, options.value_size);
+    println!("elapsed       {:.3}s", elapsed.as_secs_f64());
+    println!("throughput    {:.0} commands/s, {:.0} batches/s", commands as f64 / elapsed.as_secs_f64(), latencies.len() as f64 / elapsed.as_secs_f64());
+    println!("get hits      {}", hits);
+    for &(name, p) in &[("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p99.9", 0.999)] {
+        println!("latency {:<5} {:?}", name, percentile(&latencies, p));
+    }
+    println!("latency max   {:?}", latencies.last().cloned().unwrap_or_default());
+}
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use remcached::fuzzing::{self, Outcome};
+
+fuzz_target!(|buf: &[u8]| {
+    match fuzzing::parse_binary(buf) {
+        Outcome::Done(consumed) => assert!(consumed > 0 && consumed <= buf.len()),
+        Outcome::Error(skipped) => assert!(skipped <= buf.len()),
+        Outcome::Incomplete(needed) => assert!(needed > buf.len()),
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use remcached::fuzzing::{self, Outcome};
+
+// Never panics, never takes more than it was given, and only asks for more
+// when the buffer really is too short, whether or not bare \n ends a line
+fuzz_target!(|buf: &[u8]| {
+    for lenient in [false, true] {
+        match fuzzing::parse_text(buf, lenient) {
+            Outcome::Done(consumed) => assert!(consumed > 0 && consumed <= buf.len()),
+            Outcome::Error(skipped) => assert!(skipped > 0 && skipped <= buf.len()),
+            Outcome::Incomplete(needed) => assert!(needed > buf.len()),
+        }
+    }
+});
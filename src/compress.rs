@@ -0,0 +1,218 @@
+// Values are compressed in the LZ4 block format: a run of literals followed
+// by a back reference into what has been decoded so far, repeated, with the
+// last run being literals only. Each run starts with a token byte holding the
+// literal length and the match length minus 4, a nibble each, where 15 means
+// more length bytes follow.
+
+const MIN_MATCH: usize = 4;
+// matches end at least this far from the end of the input, and don't start
+// in the last MF_LIMIT bytes, like the reference encoder
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = 65535;
+const HASH_BITS: u32 = 12;
+
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn write_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        out.push(255);
+        length -= 255;
+    }
+    out.push(length as u8);
+}
+
+fn write_run(out: &mut Vec<u8>, literals: &[u8], reference: Option<(usize, usize)>) {
+    let match_nibble = reference.map_or(0, |(_, length)| (length - MIN_MATCH).min(15));
+    out.push(((literals.len().min(15) as u8) << 4) | match_nibble as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend(literals);
+    if let Some((offset, length)) = reference {
+        out.extend(&(offset as u16).to_le_bytes());
+        if length - MIN_MATCH >= 15 {
+            write_length(out, length - MIN_MATCH - 15);
+        }
+    }
+}
+
+// Greedy, taking the last position seen with the same four bytes
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while input.len() >= MF_LIMIT && pos <= input.len() - MF_LIMIT {
+        let sequence = read_u32(input, pos);
+        let slot = hash(sequence);
+        // positions are kept one up so 0 can mean empty
+        let candidate = table[slot];
+        table[slot] = pos + 1;
+        if candidate > 0 && pos - (candidate - 1) <= MAX_OFFSET && read_u32(input, candidate - 1) == sequence {
+            let start = candidate - 1;
+            let mut length = MIN_MATCH;
+            while pos + length < input.len() - LAST_LITERALS && input[start + length] == input[pos + length] {
+                length += 1;
+            }
+            write_run(&mut out, &input[anchor..pos], Some((pos - start, length)));
+            pos += length;
+            anchor = pos;
+        } else {
+            pos += 1;
+        }
+    }
+    write_run(&mut out, &input[anchor..], None);
+    out
+}
+
+fn read_length(input: &[u8], pos: &mut usize, mut length: usize) -> Option<usize> {
+    loop {
+        let byte = *input.get(*pos)?;
+        *pos += 1;
+        length = length.checked_add(byte as usize)?;
+        if byte != 255 {
+            return Some(length);
+        }
+    }
+}
+
+// None if the input is not a block that decodes to exactly `len` bytes
+pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+    while pos < input.len() {
+        let token = input[pos];
+        pos += 1;
+
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals = read_length(input, &mut pos, literals)?;
+        }
+        let end = pos.checked_add(literals)?;
+        out.extend(input.get(pos..end)?);
+        pos = end;
+        if pos == input.len() {
+            break;
+        }
+
+        let offset = u16::from_le_bytes([*input.get(pos)?, *input.get(pos + 1)?]) as usize;
+        pos += 2;
+        let mut length = (token & 0x0f) as usize;
+        if length == 15 {
+            length = read_length(input, &mut pos, length)?;
+        }
+        length += MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + length > len {
+            return None;
+        }
+        // the reference may overlap what it produces, so byte by byte
+        let start = out.len() - offset;
+        for i in 0..length {
+            let byte = out[start + i];
+            out.push(byte);
+        }
+    }
+    if out.len() == len { Some(out) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress};
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let compressed = compress(input);
+        assert_eq!(decompress(&compressed, input.len()).as_deref(), Some(input));
+        compressed
+    }
+
+    // xorshift, enough to be incompressible
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545f4914f6cdd1du64;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    #[test]
+    fn empty_input() {
+        assert_eq!(round_trip(b""), [0]);
+    }
+
+    #[test]
+    fn input_too_short_to_match_is_all_literals() {
+        let compressed = round_trip(b"abcabcabc");
+        assert_eq!(compressed.len(), 1 + 9);
+    }
+
+    #[test]
+    fn long_runs_shrink() {
+        for len in &[16, 270, 4096, 1 << 20] {
+            let compressed = round_trip(&vec![b'x'; *len]);
+            assert!(compressed.len() < len / 10 + 16, "{} bytes to {}", len, compressed.len());
+        }
+    }
+
+    #[test]
+    fn incompressible_input_survives() {
+        for len in &[13, 1000, 100_000] {
+            let input = noise(*len);
+            let compressed = round_trip(&input);
+            assert!(compressed.len() <= len + len / 255 + 16);
+        }
+    }
+
+    #[test]
+    fn matches_overlapping_their_own_output() {
+        // the offset is shorter than the match, which copies what it has
+        // just produced
+        let mut input = b"abc".repeat(100);
+        input.extend(b"0123456789");
+        let compressed = round_trip(&input);
+        assert!(compressed.len() < 30, "{:?}", compressed);
+        // and a match far back, near the longest offset; the zeros in
+        // between only take up one slot of the table
+        let block = noise(1000);
+        let mut input = block.clone();
+        input.extend(vec![0; 60_000]);
+        input.extend(&block);
+        input.extend(noise(20));
+        assert!(round_trip(&input).len() < 1500);
+    }
+
+    #[test]
+    fn truncated_input_is_refused() {
+        let mut input = b"the quick brown fox jumps over the lazy dog ".repeat(20);
+        input.extend(noise(300));
+        let compressed = round_trip(&input);
+        for cut in 0..compressed.len() {
+            assert_eq!(decompress(&compressed[..cut], input.len()), None, "cut at {}", cut);
+        }
+    }
+
+    #[test]
+    fn corrupt_input_is_refused() {
+        let input = b"abcdefgh".repeat(10);
+        let compressed = round_trip(&input);
+        // the wrong length either way
+        assert_eq!(decompress(&compressed, input.len() - 1), None);
+        assert_eq!(decompress(&compressed, input.len() + 1), None);
+        // a match with an offset of 0, and one reaching before the start
+        assert_eq!(decompress(&[0x10, b'a', 0, 0, 0x00], 6), None);
+        assert_eq!(decompress(&[0x10, b'a', 2, 0, 0x00], 6), None);
+        // a literal length running past the input
+        assert_eq!(decompress(&[0xf0, 255, 255, 255], 1000), None);
+        assert_eq!(decompress(&[0x50, b'a'], 5), None);
+        // garbage
+        assert_eq!(decompress(&noise(100), 1000), None);
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use net::Address;
+use proto;
+use stats::Counter;
+
+// The counters of one client, bumped by the loop serving it and read by
+// whichever loop answers stats conns
+#[derive(Debug)]
+pub struct ConnStats {
+    peer: Address,
+    pub bytes_read: Counter,
+    pub bytes_written: Counter,
+    pub commands: Counter,
+    connected: Instant,
+    // milliseconds after connecting that the client last sent anything
+    last_active: AtomicU64,
+}
+
+impl ConnStats {
+    // The client sent something just now
+    pub fn touch(&self) {
+        self.last_active.store(self.connected.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.connected.elapsed().saturating_sub(Duration::from_millis(self.last_active.load(Ordering::Relaxed)))
+    }
+}
+
+// The open connections of every loop, by loop id and token. The lock is
+// only taken as clients come and go and for stats conns itself.
+#[derive(Debug, Default)]
+pub struct Conns {
+    open: Mutex<BTreeMap<(usize, usize), Arc<ConnStats>>>,
+}
+
+impl Conns {
+    pub fn register(&self, loop_id: usize, token: usize, peer: Address) -> Arc<ConnStats> {
+        let stats = Arc::new(ConnStats {
+            peer,
+            bytes_read: Counter::default(),
+            bytes_written: Counter::default(),
+            commands: Counter::default(),
+            connected: Instant::now(),
+            last_active: AtomicU64::new(0),
+        });
+        self.open.lock().unwrap().insert((loop_id, token), stats.clone());
+        stats
+    }
+
+    pub fn unregister(&self, loop_id: usize, token: usize) {
+        self.open.lock().unwrap().remove(&(loop_id, token));
+    }
+
+    // Tokens are only unique within a loop, so every name starts with both
+    // as <loop>:<token>:
+    pub fn stats(&self) -> Vec<(String, String)> {
+        let mut stats = Vec::new();
+        for (&(loop_id, token), conn) in self.open.lock().unwrap().iter() {
+            let addr = match conn.peer {
+                Address::Tcp(ref addr) => format!("tcp:{}", addr),
+                Address::Unix(ref path) => format!("unix:{}", path.display()),
+            };
+            let prefix = format!("{}:{}", loop_id, token);
+            stats.push(proto::stat(&format!("{}:addr", prefix), addr));
+            stats.push(proto::stat(&format!("{}:bytes_read", prefix), conn.bytes_read.get()));
+            stats.push(proto::stat(&format!("{}:bytes_written", prefix), conn.bytes_written.get()));
+            stats.push(proto::stat(&format!("{}:commands", prefix), conn.commands.get()));
+            stats.push(proto::stat(&format!("{}:secs_since_last_cmd", prefix), conn.idle().as_secs()));
+        }
+        stats
+    }
+}
@@ -0,0 +1,1508 @@
+extern crate mio;
+extern crate bytes;
+
+#[macro_use]
+extern crate log;
+extern crate nom;
+extern crate libc;
+extern crate net2;
+extern crate rustls;
+
+use mio::{EventLoop, Handler, Token, EventSet, PollOpt, TryRead, TryWrite};
+use mio::util::Slab;
+
+use bytes::{Buf};
+use std::io::{self, Cursor};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod proto;
+mod proto_binary;
+mod proto_meta;
+mod metrics;
+mod tls;
+mod storage;
+mod stats;
+mod signal;
+mod net;
+mod snapshot;
+mod watch;
+mod rate;
+mod compress;
+mod conns;
+
+pub use net::Address;
+pub use proto::Item;
+pub use storage::{Shard, Storage, Store};
+use net::{Listener, Stream};
+
+#[derive(Debug, Clone, Copy)]
+enum Protocol {
+    Text,
+    Binary,
+    // scrapes of the metrics listener
+    Http,
+}
+
+#[derive(Debug)]
+enum Command {
+    Text(proto::Request),
+    Binary(proto_binary::Request),
+    Meta(proto_meta::Request),
+    Metrics(String),
+    // an error reply that is ready before anything could be parsed
+    Refused(Vec<u8>),
+}
+
+#[derive(Debug)]
+enum State {
+    Reading(Vec<u8>),
+    // the reply and whatever unparsed input was left over in the read buffer
+    Writing(Cursor<Vec<u8>>, Vec<u8>),
+    Closed,
+}
+
+impl State {
+    fn mut_read_buf(&mut self) -> &mut Vec<u8> {
+        match *self {
+            State::Reading(ref mut buf) => buf,
+            _ => panic!("connection not in reading state"),
+        }
+    }
+
+    fn read_buf(&self) -> &[u8] {
+        match *self {
+            State::Reading(ref buf) => buf,
+            _ => panic!("connection not in reading state"),
+        }
+    }
+
+    fn write_buf(&self) -> &Cursor<Vec<u8>> {
+        match *self {
+            State::Writing(ref buf, _) => buf,
+            _ => panic!("connection not in writing state"),
+        }
+    }
+
+    fn mut_write_buf(&mut self) -> &mut Cursor<Vec<u8>> {
+        match *self {
+            State::Writing(ref mut buf, _) => buf,
+            _ => panic!("connection not in Writing state"),
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.mut_read_buf().drain(..n);
+    }
+
+    fn transition_to_writing(&mut self, buf: Vec<u8>) {
+        let pending = mem::take(self.mut_read_buf());
+        *self = State::Writing(Cursor::new(buf), pending);
+    }
+
+    // Hands back the spent reply buffer once everything has been written
+    fn try_transition_to_reading(&mut self) -> Option<Vec<u8>> {
+        if self.write_buf().has_remaining() {
+            return None;
+        }
+        match mem::replace(self, State::Closed) {
+            State::Writing(reply, pending) => {
+                *self = State::Reading(pending);
+                Some(reply.into_inner())
+            }
+            state => {
+                *self = state;
+                None
+            }
+        }
+    }
+
+    fn into_buffers(self) -> Vec<Vec<u8>> {
+        match self {
+            State::Reading(buf) => vec![buf],
+            State::Writing(reply, pending) => vec![reply.into_inner(), pending],
+            State::Closed => Vec::new(),
+        }
+    }
+}
+
+pub struct Connection {
+    socket: Stream,
+    token: Token,
+    // the client, for the logs and stats conns
+    peer: Address,
+    state: State,
+    // the client has shut down its side; close once the replies are out
+    hung_up: bool,
+    // turned into a stream of watch events, anything it sends is ignored
+    watching: bool,
+    // the next shard of a metadump in progress, further commands wait
+    // until it is done
+    dumping: Option<usize>,
+    // buffered bytes required before the parser can make progress
+    needed: usize,
+    // a storage command whose data block is still to come, in whichever
+    // protocol, and the length of both, so it can be refused before the data
+    // is buffered
+    header: Option<(Command, usize)>,
+    // what is left of a refused data block, dropped as it arrives
+    skipping: usize,
+    // largest incomplete command we are willing to buffer
+    max_read_buffer: usize,
+    idle_timer: Option<mio::Timeout>,
+    // when the current reply last made progress, for dropping stuck readers
+    last_written: Instant,
+    write_timer: Option<mio::Timeout>,
+    // picked from the first byte the client sends
+    protocol: Option<Protocol>,
+    // the last reply buffer, cleared and kept for the next reply
+    spare: Vec<u8>,
+    // reported by stats conns, and when the client last sent us anything,
+    // for reaping idle connections
+    conn_stats: Arc<conns::ConnStats>,
+    // the server wide byte counters
+    stats: Arc<stats::Stats>,
+    // commands past the rate limit get an error instead of being run
+    rate: Option<rate::TokenBucket>,
+}
+
+impl Connection {
+    pub fn new(socket: Stream, peer: Address, token: Token, max_read_buffer: usize, read_buf: Vec<u8>, stats: Arc<stats::Stats>, conn_stats: Arc<conns::ConnStats>) -> Connection {
+        Connection {
+            socket,
+            token,
+            peer,
+            state: State::Reading(read_buf),
+            hung_up: false,
+            watching: false,
+            dumping: None,
+            needed: 0,
+            header: None,
+            skipping: 0,
+            max_read_buffer,
+            idle_timer: None,
+            last_written: Instant::now(),
+            write_timer: None,
+            protocol: None,
+            spare: Vec::new(),
+            conn_stats,
+            stats,
+            rate: None,
+        }
+    }
+
+    fn ready<S: Store>(&mut self, event_loop: &mut EventLoop<Remcached<S>>, events: EventSet) {
+        debug!("  connection state=:{:?}", self.state);
+
+        match self.state {
+            State::Reading(..) => {
+                if events.is_writable() {
+                    // TLS records left over from an earlier read or write
+                    if let Err(e) = self.socket.flush_pending() {
+                        error!("got an error trying to flush; err={:?}", e);
+                        self.state = State::Closed;
+                        return;
+                    }
+                }
+                if events.is_readable() {
+                    self.read(event_loop);
+                } else {
+                    self.reregister(event_loop);
+                }
+            }
+            State::Writing(..) => {
+                assert!(events.is_writable(), "unexpected events; events={:?}", events);
+                self.write(event_loop)
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn read<S: Store>(&mut self, event_loop: &mut EventLoop<Remcached<S>>) {
+        // edge triggered, so keep reading until the socket would block
+        loop {
+            if self.state.read_buf().len() >= self.max_read_buffer {
+                // the rest stays in the socket until the buffered commands are
+                // handled, rearming makes sure we hear about it again
+                debug!("read buffer full; buffered={}", self.state.read_buf().len());
+                self.reregister(event_loop);
+                break;
+            }
+            match self.socket.try_read_buf(self.state.mut_read_buf()) {
+                Ok(Some(0)) => {
+                    debug!("    read 0 bytes from client; buffered={}", self.state.read_buf().len());
+                    self.hung_up = true;
+                    break;
+                }
+                Ok(Some(n)) => {
+                    debug!("read {} bytes", n);
+                    self.conn_stats.touch();
+                    self.conn_stats.bytes_read.add(n as u64);
+                    self.stats.bytes_read.add(n as u64);
+                    if self.hung_up {
+                        // past the limit, drain what the client already sent so
+                        // the error reply isn't lost to a reset
+                        self.state.mut_read_buf().clear();
+                    }
+                    if self.skipping > 0 {
+                        let skipped = self.skipping.min(self.state.read_buf().len());
+                        self.state.consume(skipped);
+                        self.skipping -= skipped;
+                    }
+                }
+                Ok(None) => {
+                    debug!("read would block");
+                    self.reregister(event_loop);
+                    break;
+                }
+                Err(e) => {
+                    error!("got an error trying to read; err={:?}", e);
+                    self.state = State::Closed;
+                    break;
+                }
+            }
+        }
+    }
+
+    // A line without a newline keeps growing the buffer, while a storage
+    // command tells us up front how big its data block will be.
+    fn overflowed(&self) -> bool {
+        self.needed > self.max_read_buffer || self.state.read_buf().len() > self.max_read_buffer
+    }
+
+    fn parse_command(&mut self, lenient: bool) -> Option<Result<Command, proto::ParseError>> {
+        let buffered = self.state.read_buf().len();
+        if buffered < self.needed {
+            debug!("waiting for {} bytes; buffered={}", self.needed, buffered);
+            return Option::None;
+        }
+
+        let protocol = match self.protocol {
+            Some(protocol) => protocol,
+            None => {
+                let protocol = match self.state.read_buf().first() {
+                    Some(&proto_binary::MAGIC_REQUEST) => Protocol::Binary,
+                    Some(_) => Protocol::Text,
+                    None => return Option::None,
+                };
+                debug!("client speaks the {:?} protocol", protocol);
+                self.protocol = Some(protocol);
+                protocol
+            }
+        };
+
+        match protocol {
+            Protocol::Text => self.parse_text(lenient),
+            Protocol::Binary => self.parse_binary(),
+            Protocol::Http => self.parse_http(),
+        }
+    }
+
+    fn parse_text(&mut self, lenient: bool) -> Option<Result<Command, proto::ParseError>> {
+        if proto_meta::is_meta(self.state.read_buf()) {
+            return self.parse_meta(lenient);
+        }
+        match proto::parse(self.state.read_buf(), lenient) {
+            proto::Parsed::Done(command, consumed) => {
+                self.state.consume(consumed);
+                self.needed = 0;
+                Option::Some(Ok(Command::Text(command)))
+            }
+            proto::Parsed::Incomplete(needed) => {
+                self.needed = needed;
+                Option::None
+            }
+            proto::Parsed::Header(command, len) => {
+                self.needed = len;
+                self.header = Some((Command::Text(command), len));
+                Option::None
+            }
+            proto::Parsed::Error(error, skipped) => {
+                debug!("skipping {} bytes of invalid input; error={:?}", skipped, error);
+                self.state.consume(skipped);
+                self.needed = 0;
+                Option::Some(Err(error))
+            }
+        }
+    }
+
+    fn parse_meta(&mut self, lenient: bool) -> Option<Result<Command, proto::ParseError>> {
+        match proto_meta::parse(self.state.read_buf(), lenient) {
+            proto_meta::Parsed::Done(request, consumed) => {
+                self.state.consume(consumed);
+                self.needed = 0;
+                Option::Some(Ok(Command::Meta(request)))
+            }
+            proto_meta::Parsed::Incomplete(needed) => {
+                self.needed = needed;
+                Option::None
+            }
+            proto_meta::Parsed::Header(request, len) => {
+                self.needed = len;
+                self.header = Some((Command::Meta(request), len));
+                Option::None
+            }
+            proto_meta::Parsed::Error(error, skipped) => {
+                debug!("skipping {} bytes of invalid input; error={:?}", skipped, error);
+                self.state.consume(skipped);
+                self.needed = 0;
+                Option::Some(Err(error))
+            }
+        }
+    }
+
+    fn parse_binary(&mut self) -> Option<Result<Command, proto::ParseError>> {
+        match proto_binary::parse(self.state.read_buf()) {
+            proto_binary::Parsed::Done(request, consumed) => {
+                self.state.consume(consumed);
+                self.needed = 0;
+                Option::Some(Ok(Command::Binary(request)))
+            }
+            proto_binary::Parsed::Incomplete(needed) => {
+                self.needed = needed;
+                Option::None
+            }
+            proto_binary::Parsed::Header(request, len) => {
+                self.needed = len;
+                self.header = Some((Command::Binary(request), len));
+                Option::None
+            }
+            proto_binary::Parsed::Invalid(reply) => {
+                // memcached gives up on a binary client that loses framing
+                warn!("invalid binary request; closing connection");
+                self.discard_input();
+                reply.map(|reply| Ok(Command::Refused(reply)))
+            }
+        }
+    }
+
+    fn parse_http(&mut self) -> Option<Result<Command, proto::ParseError>> {
+        match metrics::parse(self.state.read_buf()) {
+            metrics::Parsed::Done(path, consumed) => {
+                self.state.consume(consumed);
+                self.needed = 0;
+                Option::Some(Ok(Command::Metrics(path)))
+            }
+            metrics::Parsed::Incomplete(needed) => {
+                self.needed = needed;
+                Option::None
+            }
+            metrics::Parsed::Invalid => {
+                warn!("invalid metrics request; closing connection");
+                self.discard_input();
+                Option::None
+            }
+        }
+    }
+
+    // Drops a refused command of `n` bytes, along with whatever of it the
+    // client has yet to send
+    fn skip(&mut self, n: usize) {
+        let buffered = self.state.read_buf().len().min(n);
+        self.state.consume(buffered);
+        self.skipping = n - buffered;
+        self.needed = 0;
+    }
+
+    // Gives up on the client's input, the connection closes once the
+    // replies so far are out
+    fn discard_input(&mut self) {
+        self.state.mut_read_buf().clear();
+        self.needed = 0;
+        self.hung_up = true;
+    }
+
+    // Watch events go out after whatever is still being written; a watcher
+    // that has fallen this far behind misses them
+    fn stream<S: Store>(&mut self, event_loop: &mut mio::EventLoop<Remcached<S>>, line: &[u8], limit: usize) {
+        match self.state {
+            State::Writing(ref mut reply, _) => {
+                if reply.remaining() <= limit {
+                    reply.get_mut().extend(line);
+                }
+            }
+            State::Reading(..) => self.reply(event_loop, line.to_vec()),
+            State::Closed => {}
+        }
+    }
+
+    fn reply<S: Store>(&mut self, event_loop: &mut mio::EventLoop<Remcached<S>>, buf: Vec<u8>) {
+        debug!("reply");
+        self.last_written = Instant::now();
+        self.state.transition_to_writing(buf);
+        self.reregister(event_loop);
+    }
+
+    // Like reading, keeps going until the reply is out or the socket would
+    // block, a partial write alone doesn't bring another event.
+    fn write<S: Store>(&mut self, event_loop: &mut mio::EventLoop<Remcached<S>>) {
+        loop {
+            match self.socket.try_write_buf(self.state.mut_write_buf()) {
+                Ok(Some(n)) => {
+                    self.conn_stats.bytes_written.add(n as u64);
+                    self.stats.bytes_written.add(n as u64);
+                    self.last_written = Instant::now();
+                    if self.state.write_buf().has_remaining() {
+                        continue;
+                    }
+                }
+                Ok(None) => {
+                    // the reply is still unfinished, so only the next
+                    // writable edge can move it along
+                    debug!("write would block");
+                    self.reregister(event_loop);
+                    return;
+                }
+                Err(e) => {
+                    error!("got an error trying to write; err={:?}", e);
+                    self.state = State::Closed;
+                    return;
+                }
+            }
+            break;
+        }
+
+        match self.state.try_transition_to_reading() {
+            // don't hold on to the memory of one huge reply
+            Some(mut reply) if reply.capacity() <= MAX_POOLED_CAPACITY => {
+                reply.clear();
+                self.spare = reply;
+            }
+            _ => {}
+        }
+        self.reregister(event_loop);
+    }
+
+    fn reregister<S: Store>(&self, event_loop: &mut EventLoop<Remcached<S>>) {
+        let mut event_set = match self.state {
+            State::Reading(..) => EventSet::readable(),
+            State::Writing(..) => EventSet::writable(),
+            _ => EventSet::none(),
+        };
+        if self.socket.wants_write() {
+            event_set = event_set | EventSet::writable();
+        }
+
+        // Why unwrap to make sure it is OK???
+        event_loop.reregister(&self.socket, self.token, event_set, PollOpt::edge() | PollOpt::oneshot()).unwrap();
+    }
+
+    fn close(&mut self) {
+        self.state = State::Closed;
+    }
+
+    fn is_reading(&self) -> bool {
+        matches!(self.state, State::Reading(..))
+    }
+
+    fn is_writing(&self) -> bool {
+        matches!(self.state, State::Writing(..))
+    }
+
+    fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+}
+
+// What every event loop works on, whichever thread it runs in
+pub struct Shared<S: Store = Storage> {
+    storage: Arc<S>,
+    cas_counter: Arc<AtomicU64>,
+    stats: Arc<stats::Stats>,
+    watch: Arc<watch::Watch>,
+    // the clients of every loop, for stats conns
+    conns: Arc<conns::Conns>,
+    // hands out the ids loops subscribe to watch events with
+    next_loop_id: Arc<AtomicUsize>,
+    // loops whose last pass over their events took longer than the overload
+    // threshold, new clients are turned away while there are any
+    overloaded_loops: Arc<AtomicUsize>,
+}
+
+impl<S: Store> Shared<S> {
+    pub fn new(storage: S) -> Shared<S> {
+        Shared {
+            storage: Arc::new(storage),
+            cas_counter: Arc::new(AtomicU64::new(0)),
+            stats: Arc::new(stats::Stats::default()),
+            watch: Arc::new(watch::Watch::default()),
+            conns: Arc::new(conns::Conns::default()),
+            next_loop_id: Arc::new(AtomicUsize::new(0)),
+            overloaded_loops: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+// Only the handles are cloned, which a derive would also want of S
+impl<S: Store> Clone for Shared<S> {
+    fn clone(&self) -> Shared<S> {
+        Shared {
+            storage: self.storage.clone(),
+            cas_counter: self.cas_counter.clone(),
+            stats: self.stats.clone(),
+            watch: self.watch.clone(),
+            conns: self.conns.clone(),
+            next_loop_id: self.next_loop_id.clone(),
+            overloaded_loops: self.overloaded_loops.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Message {
+    // a client accepted by another loop, with its address and the listener
+    // it came in on
+    Accept(Stream, Address, Token),
+    // a line for the watchers of this loop
+    Watch(Vec<u8>),
+    Shutdown,
+}
+
+pub struct Remcached<S: Store = Storage> {
+    id: usize,
+    // bind and also_bind in order with their tokens, worker loops only get
+    // connections handed to them
+    servers: Vec<(Token, Listener)>,
+    metrics: Option<Listener>,
+    // clients of the main listener are wrapped in TLS when set
+    tls: Option<Arc<rustls::ServerConfig>>,
+    // loops accepted clients are handed to in turn, when empty this loop
+    // serves its clients itself
+    workers: Vec<mio::Sender<Message>>,
+    next_worker: usize,
+    connections: Slab<Connection>,
+    // entries the slab has now, it doubles up to this loop's share of
+    // max_connections as clients come in
+    slab_size: usize,
+    max_connections: usize,
+    storage: Arc<S>,
+    cas_counter: Arc<AtomicU64>,
+    stats: Arc<stats::Stats>,
+    watch: Arc<watch::Watch>,
+    conns: Arc<conns::Conns>,
+    // connections of this loop that ran watch
+    watchers: Vec<Token>,
+    flush_timeout: Option<mio::Timeout>,
+    shutting_down: bool,
+    // buffers of closed connections, handed to new ones
+    buffers: Vec<Vec<u8>>,
+    max_read_buffer: usize,
+    max_write_buffer: usize,
+    max_keys: Option<usize>,
+    idle_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    overload_threshold: Option<Duration>,
+    // when this loop started on the events of the current pass
+    busy_since: Option<Instant>,
+    // whether this loop counts itself among the overloaded ones
+    overloaded: bool,
+    overloaded_loops: Arc<AtomicUsize>,
+    // as started, reported by stats settings
+    config: Config,
+}
+
+impl<S: Store> Remcached<S> {
+    pub fn new(servers: Vec<Listener>, metrics: Option<Listener>, tls: Option<Arc<rustls::ServerConfig>>, shared: &Shared<S>, config: &Config) -> Remcached<S> {
+        // the connection limit is split between the loops serving clients
+        let max_connections = config.max_connections.div_ceil(config.threads.max(1));
+        let slab_size = max_connections.min(INITIAL_SLAB_SIZE);
+        let slab = Slab::new_starting_at(Token(1), slab_size);
+
+        Remcached {
+            id: shared.next_loop_id.fetch_add(1, Ordering::Relaxed),
+            servers: servers.into_iter().enumerate().map(|(index, server)| (server_token(index), server)).collect(),
+            metrics,
+            tls,
+            workers: Vec::new(),
+            next_worker: 0,
+            connections: slab,
+            slab_size,
+            max_connections,
+            storage: shared.storage.clone(),
+            cas_counter: shared.cas_counter.clone(),
+            stats: shared.stats.clone(),
+            watch: shared.watch.clone(),
+            conns: shared.conns.clone(),
+            watchers: Vec::new(),
+            flush_timeout: None,
+            shutting_down: false,
+            buffers: Vec::new(),
+            max_read_buffer: config.max_read_buffer,
+            max_write_buffer: config.max_write_buffer,
+            max_keys: match config.max_keys {
+                0 => None,
+                max => Some(max),
+            },
+            idle_timeout: match config.idle_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            write_timeout: match config.write_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            overload_threshold: match config.overload_threshold {
+                0 => None,
+                millis => Some(Duration::from_millis(millis)),
+            },
+            busy_since: None,
+            overloaded: false,
+            overloaded_loops: shared.overloaded_loops.clone(),
+            config: config.clone(),
+        }
+    }
+
+    // One set of counters per open connection, of this loop and all others
+    fn conns(&self) -> proto::Response {
+        proto::Response::Stats(self.conns.stats())
+    }
+
+    // Names follow memcached where there is an equivalent. The memory limit
+    // comes from storage since cache_memlimit can change it at runtime.
+    fn settings(&self) -> proto::Response {
+        let addrs = Some(&self.config.bind).into_iter().chain(self.config.also_bind.iter());
+        let (mut inter, mut tcpport, mut domain_socket) = (Vec::new(), 0, "NULL".to_string());
+        for addr in addrs {
+            match *addr {
+                Address::Tcp(ref addr) => {
+                    inter.push(addr.ip().to_string());
+                    if tcpport == 0 {
+                        tcpport = addr.port();
+                    }
+                }
+                Address::Unix(ref path) => domain_socket = path.display().to_string(),
+            }
+        }
+        let inter = if inter.is_empty() { "NULL".to_string() } else { inter.join(",") };
+        let verbosity = match log::max_level() {
+            log::LevelFilter::Off | log::LevelFilter::Error => 0,
+            log::LevelFilter::Warn => 1,
+            log::LevelFilter::Info => 2,
+            _ => 3,
+        };
+        proto::Response::Stats(vec![
+            proto::stat("maxbytes", self.storage.memory_limit()),
+            proto::stat("maxconns", self.config.max_connections),
+            proto::stat("num_threads", self.config.threads),
+            proto::stat("tcpport", tcpport),
+            proto::stat("udpport", 0),
+            proto::stat("tcp_backlog", self.config.backlog),
+            proto::stat("inter", inter),
+            proto::stat("domain_socket", domain_socket),
+            proto::stat("verbosity", verbosity),
+            proto::stat("evictions", "on"),
+            proto::stat("cas_enabled", "yes"),
+            proto::stat("binding_protocol", "auto-negotiate"),
+            proto::stat("item_size_max", self.config.max_item_size),
+            proto::stat("max_items", self.config.max_items),
+            proto::stat("compress_threshold", self.config.compress_threshold),
+            proto::stat("max_keys", self.config.max_keys),
+            proto::stat("rate_limit", self.config.rate_limit),
+            proto::stat("idle_timeout", self.config.idle_timeout),
+            proto::stat("write_timeout", self.config.write_timeout),
+            proto::stat("overload_threshold", self.config.overload_threshold),
+            proto::stat("read_only", if self.config.read_only { "yes" } else { "no" }),
+            proto::stat("ssl_enabled", if self.config.tls_cert.is_some() { "yes" } else { "no" }),
+        ])
+    }
+
+    // Stop accepting, drop idle clients and let pending replies drain; the
+    // loop exits once the last connection is gone.
+    fn shutdown(&mut self, event_loop: &mut EventLoop<Self>) {
+        info!("shutting down");
+        self.shutting_down = true;
+        for listener in self.servers.iter().map(|(_, server)| server).chain(self.metrics.iter()) {
+            if let Err(e) = event_loop.deregister(listener) {
+                error!("failed to deregister a listener; err={:?}", e);
+            }
+        }
+        for worker in &self.workers {
+            if let Err(e) = worker.send(Message::Shutdown) {
+                error!("failed to stop a worker; err={:?}", e);
+            }
+        }
+        let idle: Vec<Token> = self.connections.iter()
+            .filter(|connection| !connection.is_writing())
+            .map(|connection| connection.token)
+            .collect();
+        for token in idle {
+            self.remove_connection(event_loop, token);
+        }
+        self.maybe_stop(event_loop);
+    }
+
+    fn busy(&mut self) {
+        if self.busy_since.is_none() {
+            self.busy_since = Some(Instant::now());
+        }
+    }
+
+    // A pass over the events that took longer than the threshold means the
+    // loop is falling behind, one that didn't that it has caught up
+    fn check_overload(&mut self) {
+        let threshold = match self.overload_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let busy = self.busy_since.take().map_or(Duration::ZERO, |since| since.elapsed());
+        let overloaded = busy > threshold;
+        if overloaded == self.overloaded {
+            return;
+        }
+        self.overloaded = overloaded;
+        if overloaded {
+            warn!("event loop {} took {}ms over its events; turning away new connections", self.id, busy.as_millis());
+            self.overloaded_loops.fetch_add(1, Ordering::Relaxed);
+        } else {
+            info!("event loop {} caught up", self.id);
+            self.overloaded_loops.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    fn maybe_stop(&self, event_loop: &mut EventLoop<Self>) {
+        if self.shutting_down && self.connections.is_empty() {
+            event_loop.shutdown();
+        }
+    }
+
+    // The listener is registered edge triggered, so every pending connection
+    // has to be taken now, there is no further event for the ones left behind
+    fn accept(&mut self, event_loop: &mut EventLoop<Self>, listener: Token) {
+        info!("the server socket is ready to accept connection");
+        loop {
+            let server = self.servers.iter().find(|&&(token, _)| token == listener).map(|(_, server)| server);
+            let accepted = match (listener, self.metrics.as_ref(), server) {
+                (METRICS, Some(metrics), _) => metrics.accept(),
+                (_, _, Some(server)) => server.accept(),
+                _ => return,
+            };
+            let (socket, peer) = match accepted {
+                Ok(Some(accepted)) => accepted,
+                Ok(None) => return,
+                Err(e) => {
+                    // out of file descriptors most likely; the clients being
+                    // served carry on, and the rest of the queue is taken on
+                    // the listener's next event
+                    error!("listener.accept() error: {}", e);
+                    return;
+                }
+            };
+            info!("accepted a connection from {}", peer);
+            // metrics are still served, they are how an overload gets noticed
+            if listener != METRICS && self.overloaded_loops.load(Ordering::Relaxed) > 0 {
+                warn!("overloaded; rejecting the connection from {}", peer);
+                self.stats.rejected_connections.incr();
+                continue;
+            }
+
+            let socket = match self.tls {
+                Some(ref config) if listener != METRICS => match tls::TlsStream::new(socket, config.clone()) {
+                    Ok(stream) => Stream::Tls(Box::new(stream)),
+                    Err(e) => {
+                        error!("failed to set up tls; err={:?}", e);
+                        continue;
+                    }
+                },
+                _ => socket,
+            };
+
+            if self.workers.is_empty() {
+                // past the connection limit this closes the socket, which
+                // still takes it off the queue
+                self.add_connection(event_loop, socket, peer, listener);
+                continue;
+            }
+            let worker = &self.workers[self.next_worker];
+            self.next_worker = (self.next_worker + 1) % self.workers.len();
+            if let Err(e) = worker.send(Message::Accept(socket, peer, listener)) {
+                error!("failed to hand a connection to a worker; err={:?}", e);
+            }
+        }
+    }
+
+    fn add_connection(&mut self, event_loop: &mut EventLoop<Self>, socket: Stream, peer: Address, listener: Token) {
+        let max_read_buffer = self.max_read_buffer;
+        if self.connections.count() == self.slab_size && self.slab_size < self.max_connections {
+            // new entries go after the existing ones, so tokens in use stay valid
+            let more = self.slab_size.min(self.max_connections - self.slab_size);
+            self.connections.grow(more);
+            self.slab_size += more;
+            debug!("grew the connection slab to {}", self.slab_size);
+        }
+        let read_buf = self.take_buffer();
+        let stats = self.stats.clone();
+        let (conns, id) = (&self.conns, self.id);
+        let token = match self.connections.insert_with(|token| {
+            let conn_stats = conns.register(id, token.0, peer.clone());
+            Connection::new(socket, peer, token, max_read_buffer, read_buf, stats, conn_stats)
+        }) {
+            Some(token) => token,
+            None => {
+                // the socket is dropped here, which closes it
+                warn!("connection limit of {} reached; rejecting client", self.connections.count());
+                self.stats.rejected_connections.incr();
+                return;
+            }
+        };
+        self.stats.curr_connections.incr();
+        if listener == METRICS {
+            self.connections[token].protocol = Some(Protocol::Http);
+        } else {
+            self.stats.total_connections.incr();
+            if self.config.rate_limit > 0 {
+                self.connections[token].rate = Some(rate::TokenBucket::new(self.config.rate_limit));
+            }
+        }
+
+        event_loop.register(
+            &self.connections[token].socket,
+            token,
+            EventSet::readable(),
+            PollOpt::edge() | PollOpt::oneshot()).unwrap();
+
+        if let Some(idle_timeout) = self.idle_timeout {
+            self.schedule_idle_check(event_loop, token, idle_timeout);
+        }
+    }
+
+    // Runs buffered commands until the reply grows past max_write_buffer, the
+    // rest wait in the read buffer until the reply has been written.
+    fn process(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
+        if self.connections[token].watching {
+            self.connections[token].state.mut_read_buf().clear();
+            return;
+        }
+        let mut response = mem::take(&mut self.connections[token].spare);
+        self.dump(token, &mut response);
+        while self.connections[token].dumping.is_none() && response.len() < self.max_write_buffer {
+            let command = match self.connections[token].parse_command(!self.config.strict) {
+                Some(command) => command,
+                None => {
+                    let connection = &mut self.connections[token];
+                    // the data block is only worth waiting for if it can be stored
+                    if let Some((header, len)) = connection.header.take() {
+                        let (storage, stats, read_only) = (&*self.storage, &self.stats, self.config.read_only);
+                        let refused = match header {
+                            Command::Text(ref command) => proto::refuse_header(command, storage, stats, read_only).map(|refused| proto::encode_text(&refused)),
+                            Command::Meta(ref request) => proto_meta::refuse_header(request, storage, stats, read_only),
+                            Command::Binary(ref request) => proto_binary::refuse_header(request, storage, stats, read_only),
+                            _ => None,
+                        };
+                        if let Some(refused) = refused {
+                            connection.conn_stats.commands.incr();
+                            connection.skip(len);
+                            response.extend(refused);
+                            continue;
+                        }
+                    }
+                    if connection.overflowed() {
+                        warn!("command exceeds the {} byte read buffer; closing connection", connection.max_read_buffer);
+                        // a binary client is answered in kind, if its header can be
+                        match connection.protocol {
+                            Some(Protocol::Binary) => response.extend(proto_binary::too_large(connection.state.read_buf()).unwrap_or_default()),
+                            _ => response.extend(proto::encode_text(&proto::ParseError::TooLarge.response(self.config.strict))),
+                        }
+                        connection.discard_input();
+                    }
+                    break;
+                }
+            };
+            self.connections[token].conn_stats.commands.incr();
+            if self.connections[token].rate.as_mut().is_some_and(|rate| !rate.take()) {
+                match command {
+                    Ok(Command::Binary(request)) => response.extend(proto_binary::temporary_failure(&request, "rate limited")),
+                    Ok(Command::Refused(reply)) => response.extend(reply),
+                    _ => response.extend(proto::encode_text(&proto::Response::ServerError("rate limited".to_string()))),
+                }
+                continue;
+            }
+            let mut command = match command {
+                Ok(Command::Text(command)) => command,
+                Ok(Command::Binary(request)) => {
+                    response.extend(proto_binary::handle(request, &*self.storage, &self.cas_counter, &self.stats, self.config.read_only));
+                    continue;
+                }
+                Ok(Command::Meta(request)) => {
+                    response.extend(proto_meta::handle(request, &*self.storage, &self.cas_counter, &self.stats, self.config.read_only));
+                    continue;
+                }
+                Ok(Command::Refused(reply)) => {
+                    response.extend(reply);
+                    continue;
+                }
+                Ok(Command::Metrics(path)) => {
+                    response.extend(metrics::handle(&path, &*self.storage, &self.stats));
+                    // one response per connection, as announced by Connection: close
+                    self.connections[token].hung_up = true;
+                    break;
+                }
+                Err(error) => {
+                    response.extend(proto::encode_text(&error.response(self.config.strict)));
+                    if error.closes_connection() {
+                        warn!("command line over {} bytes; closing connection", proto::MAX_LINE_LENGTH);
+                        self.connections[token].discard_input();
+                        break;
+                    }
+                    continue;
+                }
+            };
+            if command.is_quit() {
+                // memcached closes the connection without replying
+                self.connections[token].close();
+                break;
+            }
+            if self.max_keys.is_some_and(|max| command.key_count() > max) {
+                response.extend(proto::encode_text(&proto::Response::ClientError("too many keys".to_string())));
+                continue;
+            }
+            if command.is_flush() && !self.config.read_only {
+                self.schedule_flush(event_loop, command.flush_delay());
+            }
+            match command.stats_group() {
+                Some(proto::StatsGroup::Settings) => {
+                    response.extend(proto::encode_text(&self.settings()));
+                    continue;
+                }
+                Some(proto::StatsGroup::Conns) => {
+                    response.extend(proto::encode_text(&self.conns()));
+                    continue;
+                }
+                _ => {}
+            }
+            if command.is_metadump() {
+                self.connections[token].dumping = Some(0);
+                self.dump(token, &mut response);
+                continue;
+            }
+            command.fill_defaults(self.config.default_flags, self.config.default_exptime);
+            let fetched = if self.watch.is_active() { command.fetched_keys() } else { None };
+            let watch = command.is_watch();
+            let result = proto::handle(command, &self.connections[token].peer, &*self.storage, &self.cas_counter, &self.stats, self.config.read_only, &mut response);
+            if let (Some(keys), proto::Response::Values(values)) = (fetched, &result) {
+                for key in keys {
+                    self.watch.fetched(&key, values.size(&key));
+                }
+            }
+            proto::encode_text_into(&result, &mut response);
+            if watch {
+                self.start_watching(event_loop, token);
+                break;
+            }
+        }
+
+        if response.is_empty() {
+            self.connections[token].spare = response;
+        } else if !self.connections[token].is_closed() {
+            self.connections[token].reply(event_loop, response);
+            if let Some(write_timeout) = self.write_timeout {
+                if self.connections[token].write_timer.is_none() {
+                    self.schedule_write_check(event_loop, token, write_timeout);
+                }
+            }
+        }
+    }
+
+    // Adds whole shards of the metadump until the reply is full, the rest
+    // follows each time the reply has been written
+    fn dump(&mut self, token: Token, response: &mut Vec<u8>) {
+        while let Some(shard) = self.connections[token].dumping {
+            if response.len() >= self.max_write_buffer {
+                return;
+            }
+            if shard == self.storage.shard_count() {
+                response.extend(b"END\r\n");
+                self.connections[token].dumping = None;
+                return;
+            }
+            self.storage.for_each_in_shard(shard, |key, item, idle, fetches| {
+                response.extend(proto::metadump_line(key, item, idle, fetches));
+            });
+            self.connections[token].dumping = Some(shard + 1);
+        }
+    }
+
+    fn start_watching(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
+        let connection = &mut self.connections[token];
+        connection.watching = true;
+        connection.state.mut_read_buf().clear();
+        if self.watchers.is_empty() {
+            self.watch.subscribe(self.id, event_loop.channel());
+        }
+        self.watchers.push(token);
+    }
+
+    fn schedule_write_check(&mut self, event_loop: &mut EventLoop<Self>, token: Token, delay: Duration) {
+        match event_loop.timeout_ms(Timer::Write(token), delay.as_millis() as u64) {
+            Ok(timeout) => self.connections[token].write_timer = Some(timeout),
+            Err(e) => error!("failed to schedule write check; err={:?}", e),
+        }
+    }
+
+    // A client that stops reading leaves its reply stuck in the socket. Every
+    // partial write pushes the deadline out, so only a reply that has made no
+    // progress at all for the whole timeout gets the connection dropped.
+    fn check_write(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
+        let write_timeout = match self.write_timeout {
+            Some(write_timeout) => write_timeout,
+            None => return,
+        };
+        if !self.connections.contains(token) {
+            return;
+        }
+
+        let connection = &mut self.connections[token];
+        connection.write_timer = None;
+        if !connection.is_writing() {
+            return;
+        }
+        let stalled = connection.last_written.elapsed();
+        if stalled < write_timeout {
+            self.schedule_write_check(event_loop, token, write_timeout - stalled);
+        } else {
+            info!("closing connection whose reply made no progress for {:?}", stalled);
+            self.remove_connection(event_loop, token);
+            self.maybe_stop(event_loop);
+        }
+    }
+
+    fn schedule_idle_check(&mut self, event_loop: &mut EventLoop<Self>, token: Token, delay: Duration) {
+        match event_loop.timeout_ms(Timer::Idle(token), delay.as_millis() as u64) {
+            Ok(timeout) => self.connections[token].idle_timer = Some(timeout),
+            Err(e) => error!("failed to schedule idle check; err={:?}", e),
+        }
+    }
+
+    // Only the deadline is rescheduled on activity, so a check can fire early
+    // and find the connection has been busy in the meantime.
+    fn check_idle(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
+        let idle_timeout = match self.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return,
+        };
+        if !self.connections.contains(token) {
+            return;
+        }
+
+        let connection = &mut self.connections[token];
+        connection.idle_timer = None;
+        let idle = connection.conn_stats.idle();
+        if connection.is_writing() {
+            // a reply is still going out, don't cut it off
+            self.schedule_idle_check(event_loop, token, idle_timeout);
+        } else if idle < idle_timeout {
+            self.schedule_idle_check(event_loop, token, idle_timeout - idle);
+        } else {
+            info!("closing connection idle for {:?}", idle);
+            self.remove_connection(event_loop, token);
+            self.maybe_stop(event_loop);
+        }
+    }
+
+    fn remove_connection(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
+        if let Some(connection) = self.connections.remove(token) {
+            self.stats.curr_connections.decr();
+            self.conns.unregister(self.id, token.0);
+            if let Some(position) = self.watchers.iter().position(|&watcher| watcher == token) {
+                self.watchers.remove(position);
+                if self.watchers.is_empty() {
+                    self.watch.unsubscribe(self.id);
+                }
+            }
+            // the token is up for reuse, so the check must not outlive it
+            for timeout in connection.idle_timer.into_iter().chain(connection.write_timer) {
+                event_loop.clear_timeout(timeout);
+            }
+            self.recycle(connection.spare);
+            for buf in connection.state.into_buffers() {
+                self.recycle(buf);
+            }
+        }
+    }
+
+    fn take_buffer(&mut self) -> Vec<u8> {
+        self.buffers.pop().unwrap_or_else(|| Vec::with_capacity(INITIAL_BUFFER_CAPACITY))
+    }
+
+    // Only a bounded number of modestly sized buffers are kept around, the
+    // rest are freed with their connection.
+    fn recycle(&mut self, mut buf: Vec<u8>) {
+        if self.buffers.len() < BUFFER_POOL_SIZE && buf.capacity() <= MAX_POOLED_CAPACITY {
+            buf.clear();
+            self.buffers.push(buf);
+        }
+    }
+
+    fn schedule_flush(&mut self, event_loop: &mut EventLoop<Self>, delay: Option<u64>) {
+        // a newer flush_all always supersedes a pending one
+        if let Some(timeout) = self.flush_timeout.take() {
+            event_loop.clear_timeout(timeout);
+        }
+        if let Some(delay) = delay {
+            let millis = match delay.checked_mul(1000) {
+                Some(millis) => millis,
+                None => {
+                    error!("flush_all delay of {}s is out of range", delay);
+                    return;
+                }
+            };
+            match event_loop.timeout_ms(Timer::FlushAll, millis) {
+                Ok(timeout) => self.flush_timeout = Some(timeout),
+                Err(e) => error!("failed to schedule flush_all; err={:?}", e),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Timer {
+    FlushAll,
+    Idle(Token),
+    Write(Token),
+}
+
+const INITIAL_BUFFER_CAPACITY: usize = 4096;
+const INITIAL_SLAB_SIZE: usize = 64;
+const BUFFER_POOL_SIZE: usize = 64;
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+const SERVER: Token = Token(0);
+// mio keeps Token(usize::MAX) for itself
+const SIGNAL: Token = Token(usize::MAX - 1);
+const METRICS: Token = Token(usize::MAX - 2);
+
+// The first listener is SERVER, any further ones count down below METRICS,
+// far above the tokens of connections
+fn server_token(index: usize) -> Token {
+    match index {
+        0 => SERVER,
+        index => Token(usize::MAX - 2 - index),
+    }
+}
+
+impl<S: Store> Handler for Remcached<S> {
+    type Timeout = Timer;
+    type Message = Message;
+
+    fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token,
+             events: EventSet) {
+        self.busy();
+
+        debug!("Token: {:?}", token);
+        match token {
+            SIGNAL => self.shutdown(event_loop),
+            METRICS => self.accept(event_loop, token),
+            _ if self.servers.iter().any(|&(server, _)| server == token) => self.accept(event_loop, token),
+            _ => {
+                if !self.connections.contains(token) {
+                    // dropped earlier in this same batch of events
+                    return;
+                }
+                self.connections[token].ready(event_loop, events);
+                // also picks up commands left buffered while the last reply went out
+                if self.connections[token].is_reading() {
+                    self.process(event_loop, token);
+                }
+
+                let connection = &mut self.connections[token];
+                if (self.shutting_down || connection.hung_up) && !connection.is_writing() {
+                    connection.close();
+                }
+
+                if self.connections[token].is_closed() {
+                    self.remove_connection(event_loop, token);
+                }
+                self.maybe_stop(event_loop);
+            }
+        }
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<Self>, timeout: Timer) {
+        self.busy();
+        match timeout {
+            Timer::FlushAll => {
+                info!("running delayed flush_all");
+                self.flush_timeout = None;
+                self.storage.clear();
+            }
+            Timer::Idle(token) => self.check_idle(event_loop, token),
+            Timer::Write(token) => self.check_write(event_loop, token),
+        }
+    }
+
+    fn notify(&mut self, event_loop: &mut EventLoop<Self>, message: Message) {
+        self.busy();
+        match message {
+            Message::Accept(socket, peer, listener) => self.add_connection(event_loop, socket, peer, listener),
+            Message::Watch(line) => {
+                for &token in &self.watchers {
+                    self.connections[token].stream(event_loop, &line, self.max_write_buffer);
+                }
+            }
+            Message::Shutdown => self.shutdown(event_loop),
+        }
+    }
+
+    fn tick(&mut self, _event_loop: &mut EventLoop<Self>) {
+        self.check_overload();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // host:port or the path of a unix socket
+    pub bind: Address,
+    // more addresses served the same way, like [::1]:9922 next to
+    // 127.0.0.1:9922
+    pub also_bind: Vec<Address>,
+    // event loops serving clients, more than one adds a loop that only accepts
+    pub threads: usize,
+    pub max_connections: usize,
+    // connections the kernel queues up before they are accepted
+    pub backlog: usize,
+    // least recently used items are evicted past this many
+    pub max_items: usize,
+    // largest value accepted by the storage commands, in bytes
+    pub max_item_size: usize,
+    // total key and value bytes kept before evicting, in bytes
+    pub memory_limit: usize,
+    // values at least this many bytes are kept compressed when that makes
+    // them smaller, 0 for never
+    pub compress_threshold: usize,
+    // a client sending more than this without completing a command is dropped
+    pub max_read_buffer: usize,
+    // no more commands are run for a client once this much reply is pending
+    pub max_write_buffer: usize,
+    // flags and exptime for sets in the short form, `set <key> <value>`,
+    // which has no room for them
+    pub default_flags: u32,
+    pub default_exptime: i64,
+    // keys a single get may ask for, 0 for no limit
+    pub max_keys: usize,
+    // commands a second each client may send, with bursts of up to a
+    // second's worth, 0 for no limit. Commands past it are answered with
+    // SERVER_ERROR rate limited, or a temporary failure in binary.
+    pub rate_limit: u64,
+    // seconds without hearing from a client before it is disconnected, 0 never
+    pub idle_timeout: u64,
+    // seconds a reply may go without any of it being written before the
+    // client is disconnected, 0 never
+    pub write_timeout: u64,
+    // milliseconds an event loop may spend on one pass over its events;
+    // past that new clients are closed right away until it catches up, 0
+    // never
+    pub overload_threshold: u64,
+    // permission bits for the unix socket files, like 0700 to keep other
+    // users out; otherwise they are left as the umask has them
+    pub unix_mode: Option<u32>,
+    // serve Prometheus metrics over HTTP here as well
+    pub metrics_bind: Option<Address>,
+    // certificate chain and private key in PEM, enables TLS on the main listener
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    // the cache is saved here on shutdown and loaded back on startup
+    pub snapshot: Option<PathBuf>,
+    // follow the protocol to the letter: unknown commands get a bare ERROR
+    // and lines have to end in \r\n. Otherwise the reply names an unknown
+    // verb and a bare \n ends a line too.
+    pub strict: bool,
+    // commands that change the cache are refused, it is only filled from the
+    // snapshot
+    pub read_only: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let max_item_size = 1024 * 1024;
+        Config {
+            bind: Address::Tcp(SocketAddr::from(([127, 0, 0, 1], 9922))),
+            also_bind: Vec::new(),
+            threads: 1,
+            max_connections: 1024,
+            backlog: 1024,
+            max_items: 1 << 20,
+            max_item_size,
+            memory_limit: 64 * 1024 * 1024,
+            compress_threshold: 0,
+            // room for the largest value plus its command line
+            max_read_buffer: max_item_size + 4096,
+            max_write_buffer: 1024 * 1024,
+            default_flags: 0,
+            default_exptime: 0,
+            max_keys: 0,
+            rate_limit: 0,
+            idle_timeout: 0,
+            write_timeout: 0,
+            overload_threshold: 0,
+            unix_mode: None,
+            metrics_bind: None,
+            tls_cert: None,
+            tls_key: None,
+            snapshot: None,
+            strict: true,
+            read_only: false,
+        }
+    }
+}
+
+pub fn run(addr: SocketAddr) -> io::Result<()> {
+    serve(Config { bind: addr.into(), ..Config::default() })
+}
+
+pub fn serve(config: Config) -> io::Result<()> {
+    let storage = Storage::new(config.max_items, config.max_item_size, config.memory_limit, config.compress_threshold);
+    serve_with(config, storage)
+}
+
+// Like serve, calling ready once the listeners are bound and the server is
+// about to take clients
+pub fn serve_notify<F: FnOnce()>(config: Config, ready: F) -> io::Result<()> {
+    let storage = Storage::new(config.max_items, config.max_item_size, config.memory_limit, config.compress_threshold);
+    serve_store(config, storage, ready)
+}
+
+// Like serve, with the items kept in the given store
+pub fn serve_with<S: Store>(config: Config, storage: S) -> io::Result<()> {
+    serve_store(config, storage, || {})
+}
+
+fn serve_store<S: Store, F: FnOnce()>(config: Config, storage: S, ready: F) -> io::Result<()> {
+    let tls = match (config.tls_cert.as_ref(), config.tls_key.as_ref()) {
+        (Some(cert), Some(key)) => Some(tls::load_config(cert, key)?),
+        (None, None) => None,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "tls needs both a certificate and a key")),
+    };
+
+    let addrs: Vec<Address> = Some(config.bind.clone()).into_iter().chain(config.also_bind.iter().cloned()).collect();
+    let servers = Listener::bind_all(&addrs, config.backlog, config.unix_mode)?;
+
+    let mut e = EventLoop::new()?;
+
+    for (index, server) in servers.iter().enumerate() {
+        e.register(server, server_token(index), EventSet::readable(), PollOpt::edge())?;
+    }
+
+    let metrics = match config.metrics_bind {
+        Some(ref addr) => {
+            let metrics = Listener::bind(addr, config.backlog, false, None)?;
+            e.register(&metrics, METRICS, EventSet::readable(), PollOpt::edge())?;
+            info!("serving metrics on {}", addr);
+            Some(metrics)
+        }
+        None => None,
+    };
+
+    let signals = signal::install()?;
+    e.register(&signals, SIGNAL, EventSet::readable(), PollOpt::edge())?;
+
+    for addr in &addrs {
+        info!("running remcache server on {}", addr);
+    }
+
+    let shared = Shared::new(storage);
+
+    if let Some(ref path) = config.snapshot {
+        // a cache can always start cold, so a bad snapshot doesn't stop us
+        match snapshot::load(&*shared.storage, path) {
+            Ok((items, max_cas)) => {
+                info!("loaded {} items from {}", items, path.display());
+                shared.cas_counter.store(max_cas, Ordering::Relaxed);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => info!("no snapshot at {}", path.display()),
+            Err(e) => error!("failed to load snapshot {}; err={:?}", path.display(), e),
+        }
+    }
+
+    let mut remcached = Remcached::new(servers, metrics, tls, &shared, &config);
+    let mut workers = Vec::new();
+    if config.threads > 1 {
+        for id in 0..config.threads {
+            let (sender, worker) = spawn_worker(id, &shared, &config)?;
+            remcached.workers.push(sender);
+            workers.push(worker);
+        }
+        info!("serving clients on {} threads", config.threads);
+    }
+
+    ready();
+    e.run(&mut remcached)?;
+
+    for worker in workers {
+        worker.join().map_err(|_| io::Error::other("a worker thread panicked"))??;
+    }
+
+    if let Some(ref path) = config.snapshot {
+        let items = snapshot::save(&*shared.storage, path)?;
+        info!("saved {} items to {}", items, path.display());
+    }
+    Ok(())
+}
+
+// A loop of its own on a new thread, serving the clients the accepting loop
+// hands it through the returned channel
+fn spawn_worker<S: Store>(id: usize, shared: &Shared<S>, config: &Config) -> io::Result<(mio::Sender<Message>, thread::JoinHandle<io::Result<()>>)> {
+    let shared = shared.clone();
+    let config = config.clone();
+    let (sender, receiver) = mpsc::channel();
+    let worker = thread::Builder::new().name(format!("worker-{}", id)).spawn(move || {
+        let mut event_loop = EventLoop::new()?;
+        // the receiving end is waiting, so this can't fail
+        sender.send(event_loop.channel()).unwrap();
+        let mut remcached = Remcached::new(Vec::new(), None, None, &shared, &config);
+        event_loop.run(&mut remcached)
+    })?;
+    match receiver.recv() {
+        Ok(channel) => Ok((channel, worker)),
+        // the loop couldn't be created, the thread has the reason
+        Err(_) => match worker.join() {
+            Ok(Err(e)) => Err(e),
+            _ => Err(io::Error::other("a worker thread failed to start")),
+        },
+    }
+}
+
+// Entry points for the fuzz targets under fuzz/, cargo fuzz builds with
+// --cfg fuzzing
+#[cfg(fuzzing)]
+pub mod fuzzing {
+    use {proto, proto_binary, proto_meta};
+
+    // How many bytes a parse took or asked for
+    #[derive(Debug)]
+    pub enum Outcome {
+        Done(usize),
+        Incomplete(usize),
+        Error(usize),
+    }
+
+    // The text protocol, meta commands included, as a connection parses it
+    pub fn parse_text(buf: &[u8], lenient: bool) -> Outcome {
+        if proto_meta::is_meta(buf) {
+            return match proto_meta::parse(buf, lenient) {
+                proto_meta::Parsed::Done(_, consumed) => Outcome::Done(consumed),
+                proto_meta::Parsed::Incomplete(needed) => Outcome::Incomplete(needed),
+                proto_meta::Parsed::Header(_, len) => Outcome::Incomplete(len),
+                proto_meta::Parsed::Error(_, skipped) => Outcome::Error(skipped),
+            };
+        }
+        match proto::parse(buf, lenient) {
+            proto::Parsed::Done(_, consumed) => Outcome::Done(consumed),
+            proto::Parsed::Incomplete(needed) => Outcome::Incomplete(needed),
+            proto::Parsed::Header(_, len) => Outcome::Incomplete(len),
+            proto::Parsed::Error(_, skipped) => Outcome::Error(skipped),
+        }
+    }
+
+    pub fn parse_binary(buf: &[u8]) -> Outcome {
+        match proto_binary::parse(buf) {
+            proto_binary::Parsed::Done(_, consumed) => Outcome::Done(consumed),
+            proto_binary::Parsed::Incomplete(needed) => Outcome::Incomplete(needed),
+            proto_binary::Parsed::Header(_, len) => Outcome::Incomplete(len),
+            proto_binary::Parsed::Invalid(_) => Outcome::Error(buf.len()),
+        }
+    }
+}
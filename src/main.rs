@@ -1,252 +1,269 @@
-extern crate mio;
-extern crate bytes;
-
-#[macro_use]
-extern crate log;
+extern crate remcached;
 extern crate env_logger;
-extern crate nom;
-
-use mio::{EventLoop, Handler, Token, EventSet, PollOpt, TryRead, TryWrite};
-use mio::tcp::*;
-use mio::util::Slab;
-
-use bytes::{Buf};
-use std::io::Cursor;
-use std::collections::HashMap;
-
-mod proto;
-
-#[derive(Debug)]
-enum State {
-    Reading(Vec<u8>),
-    Writing(Cursor<Vec<u8>>),
-    Closed,
-}
-
-impl State {
-    fn mut_read_buf(&mut self) -> &mut Vec<u8> {
-        match *self {
-            State::Reading(ref mut buf) => buf,
-            _ => panic!("connection not in reading state"),
-        }
-    }
-
-    fn read_buf(&self) -> &[u8] {
-        match *self {
-            State::Reading(ref buf) => buf,
-            _ => panic!("connection not in reading state"),
+extern crate log;
+extern crate libc;
+
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io::Write;
+use std::str::FromStr;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::process;
+
+// Either `--name value` or `--name=value`, the last one given wins
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = env::args().skip(1);
+    let mut value = None;
+    while let Some(arg) = args.next() {
+        if arg == name {
+            value = args.next();
+        } else if let Some(rest) = arg.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')) {
+            value = Some(rest.to_string());
         }
     }
+    value
+}
 
-    fn write_buf(&self) -> &Cursor<Vec<u8>> {
-        match *self {
-            State::Writing(ref buf) => buf,
-            _ => panic!("connection not in writing state"),
-        }
-    }
+fn has_flag(name: &str) -> bool {
+    env::args().skip(1).any(|arg| arg == name)
+}
 
-    fn mut_write_buf(&mut self) -> &mut Cursor<Vec<u8>> {
-        match *self {
-            State::Writing(ref mut buf) => buf,
-            _ => panic!("connection not in Writing state"),
-        }
-    }
+// --bind on the command line wins over $REMCACHED_BIND, a comma separated
+// list of host:port or unix socket paths like 0.0.0.0:9922,[::]:9922
+fn bind_addresses(default: remcached::Address) -> (remcached::Address, Vec<remcached::Address>) {
+    let binds = match arg_value("--bind").or_else(|| env::var("REMCACHED_BIND").ok()) {
+        Some(binds) => binds,
+        None => return (default, Vec::new()),
+    };
+    let mut addrs = binds.split(',').map(|bind| {
+        bind.parse().unwrap_or_else(|e| panic!("invalid bind address {:?}: {}", bind, e))
+    });
+    let first = addrs.next().expect("split yields at least one address");
+    (first, addrs.collect())
+}
 
-    fn parse_command(&self) -> Option<proto::Request> {
-        return proto::parse(self.read_buf());
+fn parse_arg<T>(name: &str, default: T) -> T where T: FromStr, T::Err: Display {
+    match arg_value(name) {
+        Some(value) => value.parse().unwrap_or_else(|e| panic!("invalid {} {:?}: {}", name, value, e)),
+        None => default,
     }
+}
 
-    fn transition_to_writing(&mut self, buf: Vec<u8>) {
-        *self = State::Writing(Cursor::new(buf));
-    }
+// A byte count with an optional k, m or g suffix, like 512k
+fn parse_size(value: &str) -> Result<usize, String> {
+    let (digits, unit) = match value.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&value[..value.len() - 1], 1 << 10),
+        Some('m') => (&value[..value.len() - 1], 1 << 20),
+        Some('g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    digits.parse::<usize>().map_err(|e| e.to_string())?
+        .checked_mul(unit).ok_or_else(|| "too large".to_string())
+}
 
-    fn try_transition_to_reading(&mut self) {
-        if !self.write_buf().has_remaining() {
-            *self = State::Reading(Vec::new());
-        }
+fn size_arg(name: &str, default: usize) -> usize {
+    match arg_value(name) {
+        Some(value) => parse_size(&value).unwrap_or_else(|e| panic!("invalid {} {:?}: {}", name, value, e)),
+        None => default,
     }
 }
 
-#[derive(Debug)]
-struct Connection {
-    socket: TcpStream,
-    token: Token,
-    state: State,
+// A count of megabytes, like memcached's -m, in bytes
+fn megabytes_arg(name: &str, default: usize) -> usize {
+    let megabytes: usize = parse_arg(name, default >> 20);
+    megabytes.checked_mul(1 << 20).unwrap_or_else(|| panic!("invalid {} {:?}: too large", name, megabytes))
 }
 
-impl Connection {
-    fn new(socket: TcpStream, token: Token) -> Connection {
-        Connection {
-            socket: socket,
-            token: token,
-            state: State::Reading(vec![]),
-        }
+fn config() -> remcached::Config {
+    let default = remcached::Config::default();
+    let max_item_size = size_arg("--max-item-size", default.max_item_size);
+    let (bind, also_bind) = bind_addresses(default.bind.clone());
+    remcached::Config {
+        bind,
+        also_bind,
+        threads: parse_arg("--threads", default.threads),
+        max_connections: parse_arg("--max-connections", default.max_connections),
+        backlog: parse_arg("--backlog", default.backlog),
+        max_items: parse_arg("--max-items", default.max_items),
+        max_item_size,
+        memory_limit: megabytes_arg("--memory-limit", default.memory_limit),
+        compress_threshold: size_arg("--compress", default.compress_threshold),
+        // room for the largest value plus its command line
+        max_read_buffer: parse_arg("--max-read-buffer", max_item_size + 4096),
+        max_write_buffer: parse_arg("--max-write-buffer", default.max_write_buffer),
+        default_flags: parse_arg("--default-flags", default.default_flags),
+        default_exptime: parse_arg("--default-exptime", default.default_exptime),
+        max_keys: parse_arg("--max-keys", default.max_keys),
+        rate_limit: parse_arg("--rate-limit", default.rate_limit),
+        idle_timeout: parse_arg("--idle-timeout", default.idle_timeout),
+        write_timeout: parse_arg("--write-timeout", default.write_timeout),
+        overload_threshold: parse_arg("--overload-threshold", default.overload_threshold),
+        unix_mode: arg_value("--unixsocket-perms").map(|mode| match u32::from_str_radix(&mode, 8) {
+            Ok(mode) if mode <= 0o777 => mode,
+            _ => panic!("invalid --unixsocket-perms {:?}: expected octal permission bits like 0700", mode),
+        }),
+        metrics_bind: arg_value("--metrics-bind").map(|bind| {
+            bind.parse().unwrap_or_else(|e| panic!("invalid metrics address {:?}: {}", bind, e))
+        }),
+        tls_cert: arg_value("--cert").map(PathBuf::from),
+        tls_key: arg_value("--key").map(PathBuf::from),
+        snapshot: arg_value("--snapshot").map(PathBuf::from),
+        strict: parse_arg("--strict", default.strict),
+        read_only: has_flag("--read-only"),
     }
+}
 
-    fn ready(&mut self, event_loop: &mut EventLoop<Remcached>, events: EventSet) -> Option<proto::Request> {
-        debug!("  connection state=:{:?}", self.state);
-
-        match self.state {
-            State::Reading(..) => {
-                assert!(events.is_readable(), "unexpected events; events={:?}", events);
-                return self.read(event_loop);
-            }
-            State::Writing(..) => {
-                assert!(events.is_writable(), "unexpected events; events={:?}", events);
-                self.write(event_loop)
-            }
-            _ => unimplemented!(),
+// A JSON string literal, with the escapes JSON requires
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
         }
-        return Option::None;
     }
+    out.push('"');
+    out
+}
 
-    fn read(&mut self, event_loop: &mut EventLoop<Remcached>)-> Option<proto::Request> {
-        match self.socket.try_read_buf(self.state.mut_read_buf()) {
-            Ok(Some(0)) => {
-                debug!("    read 0 bytes from client; buffered={}", self.state.read_buf().len());
-            }
-            Ok(Some(n)) => {
-                debug!("read {} bytes", n);
-                self.reregister(event_loop);
-            }
-            Ok(None) => {
-                debug!("read nothing");
-                self.reregister(event_loop);
-            }
-            Err(e) => {
-                self.state = State::Closed;
-                panic!("got an error trying to read; err={:?}", e);
+// Messages like "command method=GET key=a latency_us=12" end in key=value
+// pairs, those become fields of their own. A message whose tail doesn't
+// split cleanly, like an error with spaces in it, is kept whole.
+fn json_line(timestamp: &str, level: log::Level, target: &str, message: &str) -> String {
+    let mut tokens: Vec<&str> = message.split(' ').collect();
+    let mut fields = Vec::new();
+    while let Some(token) = tokens.last() {
+        match token.find('=') {
+            Some(eq) if eq > 0 && token[..eq].chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => {
+                fields.push((&token[..eq], &token[eq + 1..]));
+                tokens.pop();
             }
+            _ => break,
         }
-        return self.state.parse_command();
     }
-
-    fn reply(&mut self, event_loop: &mut mio::EventLoop<Remcached>, buf: Vec<u8>) {
-        debug!("reply");
-        self.state.transition_to_writing(buf);
-        self.reregister(event_loop);
+    let message = if tokens.iter().any(|token| token.contains('=')) {
+        fields.clear();
+        message.to_string()
+    } else {
+        tokens.join(" ")
+    };
+
+    let mut line = format!("{{\"ts\":{},\"level\":{},\"target\":{},\"msg\":{}",
+                           json_string(timestamp), json_string(&level.to_string()), json_string(target), json_string(&message));
+    for (name, value) in fields.iter().rev() {
+        line.push_str(&format!(",{}:{}", json_string(name), json_string(value)));
     }
+    line.push('}');
+    line
+}
 
-    fn write(&mut self, event_loop: &mut mio::EventLoop<Remcached>) {
-        match self.socket.try_write_buf(self.state.mut_write_buf()) {
-            Ok(Some(_)) => {
-                self.state.try_transition_to_reading();
-                self.reregister(event_loop);
-            }
-            Ok(None) => {
-                self.state.try_transition_to_reading();
-                self.reregister(event_loop);
-            }
-            Err(e) => {
-                panic!("got an error trying to write; err={:?}", e);
-            }
+// The verbosity command moves log's global max level at runtime, which only
+// helps if the logger itself lets the extra lines through. Without RUST_LOG
+// everything up to debug passes the logger and the max level starts at info,
+// so each command's line with its latency shows by default.
+// --log-format text is env_logger's own, json writes one object per line.
+fn init_logging() {
+    let mut builder = env_logger::Builder::new();
+    match arg_value("--log-format").as_deref() {
+        None | Some("text") => {}
+        Some("json") => {
+            builder.format(|buf, record| {
+                let line = json_line(&buf.timestamp().to_string(), record.level(), record.target(), &record.args().to_string());
+                writeln!(buf, "{}", line)
+            });
         }
+        Some(format) => panic!("invalid --log-format {:?}: expected text or json", format),
     }
-
-    fn reregister(&self, event_loop: &mut EventLoop<Remcached>) {
-        let event_set = match self.state {
-            State::Reading(..) => EventSet::readable(),
-            State::Writing(..) => EventSet::writable(),
-            _ => EventSet::none(),
-        };
-
-        // Why unwrap to make sure it is OK???
-        event_loop.reregister(&self.socket, self.token, event_set, PollOpt::oneshot()).unwrap();
-    }
-
-    fn is_closed(&self) -> bool {
-        match self.state {
-            State::Closed => true,
-            _ => false,
+    match env::var("RUST_LOG") {
+        Ok(filters) => {
+            builder.parse(&filters).init();
+        }
+        Err(_) => {
+            builder.filter(None, log::LevelFilter::Debug).init();
+            log::set_max_level(log::LevelFilter::Info);
         }
     }
 }
 
-struct Remcached {
-    server: TcpListener,
-    connections: Slab<Connection>,
-    storage: HashMap<String, String>,
-}
-
-impl Remcached {
-    fn new(server: TcpListener) -> Remcached {
-        let slab = Slab::new_starting_at(Token(1), 1024);
-
-        Remcached {
-            server: server,
-            connections: slab,
-            storage: HashMap::new(),
+// Forks into the background, the child carries on in a session of its own.
+// The parent waits to hear over a pipe that the child is up, which it is
+// told through the returned end, and exits 0 only then, so a bind that fails
+// fails the command that started it.
+fn daemonize() -> libc::c_int {
+    unsafe {
+        let mut fds = [0; 2];
+        if libc::pipe(fds.as_mut_ptr()) == -1 {
+            panic!("pipe failed: {}", std::io::Error::last_os_error());
+        }
+        match libc::fork() {
+            -1 => panic!("fork failed: {}", std::io::Error::last_os_error()),
+            0 => {}
+            _ => {
+                libc::close(fds[1]);
+                let mut up = 0u8;
+                // the pipe closes without a byte when the child dies, which
+                // has said why on the stderr we share
+                while libc::read(fds[0], &mut up as *mut u8 as *mut libc::c_void, 1) == -1
+                    && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {}
+                process::exit(if up == 1 { 0 } else { 1 });
+            },
+        }
+        libc::close(fds[0]);
+        if libc::setsid() == -1 {
+            panic!("setsid failed: {}", std::io::Error::last_os_error());
         }
+        fds[1]
     }
 }
 
-const SERVER: Token = Token(0);
-
-impl Handler for Remcached {
-    type Timeout = ();
-    type Message = ();
-
-    fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token,
-             events: EventSet) {
-
-        debug!("Token: {:?}", token);
-        match token {
-            SERVER =>  {
-                info!("the server socket is ready to accept connection");
-                match self.server.accept() {
-                    Ok(Some((socket, _))) => {
-                        debug!("accepted a socket");
-
-                        let token = self.connections
-                            .insert_with(|token| Connection::new(socket, token))
-                            .unwrap();
-
-                        event_loop.register(
-                            &self.connections[token].socket,
-                            token,
-                            EventSet::readable(),
-                            PollOpt::edge() | PollOpt::oneshot()).unwrap();
-                    }
-                    Ok(None) => {
-                        warn!("the server socket wasn't actually ready")
-                    }
-                    Err(e) => {
-                        error!("listener.accept() error: {}", e);
-                        event_loop.shutdown();
-                    }
-                }
-            }
-            _ => {
-                let res = self.connections[token].ready(event_loop, events);
-                match res {
-                    Some(command) => {
-                        self.connections[token].reply(
-                            event_loop, proto::handle(command, &mut self.storage)
-                        );
-                    },
-                    None => {},
-                }
-
-                if self.connections[token].is_closed() {
-                    let _ = self.connections.remove(token);
-                }
-            }
+// Once the daemon is up: stdin, stdout and stderr, and so the text log, go
+// to /dev/null and the waiting parent is let go. The working directory stays
+// so relative paths keep working.
+fn detach(ready: libc::c_int) {
+    unsafe {
+        let null = CString::new("/dev/null").unwrap();
+        let fd = libc::open(null.as_ptr(), libc::O_RDWR);
+        if fd == -1 {
+            panic!("cannot open /dev/null: {}", std::io::Error::last_os_error());
+        }
+        for target in 0..3 {
+            libc::dup2(fd, target);
         }
+        if fd > 2 {
+            libc::close(fd);
+        }
+        libc::write(ready, &1u8 as *const u8 as *const libc::c_void, 1);
+        libc::close(ready);
     }
 }
 
 fn main()
 {
-    env_logger::init();
-    let server = TcpListener::bind(&"127.0.0.1:9922".parse().unwrap()).unwrap();
-
-    let mut e = EventLoop::new().unwrap();
-
-    e.register(&server, SERVER, EventSet::readable(), PollOpt::edge()).unwrap();
-
-    info!("running remcache server");
-
-    let mut remcached = Remcached::new(server);
-
-    e.run(&mut remcached).ok().expect("Failed to start event loop");
+    init_logging();
+
+    let config = config();
+    let daemon = if has_flag("--daemonize") { Some(daemonize()) } else { None };
+    // written once the listeners are bound, after forking so it holds the
+    // pid that keeps running
+    let pidfile = arg_value("--pidfile").map(PathBuf::from);
+    let result = remcached::serve_notify(config, || {
+        if let Some(ref path) = pidfile {
+            fs::write(path, format!("{}\n", process::id())).unwrap_or_else(|e| panic!("cannot write pidfile {}: {}", path.display(), e));
+        }
+        if let Some(ready) = daemon {
+            detach(ready);
+        }
+    });
+    if let Some(ref path) = pidfile {
+        let _ = fs::remove_file(path);
+    }
+    result.expect("Failed to start event loop");
 }
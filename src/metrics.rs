@@ -0,0 +1,62 @@
+use std::str;
+
+use storage::Store;
+use stats::Stats;
+
+pub enum Parsed {
+    // the request path and the number of bytes the request took up
+    Done(String, usize),
+    // the buffer has to grow to at least this many bytes before parsing again
+    Incomplete(usize),
+    Invalid,
+}
+
+// Only the request line matters, headers are skipped and a body is never
+// expected since scrapers just GET.
+pub fn parse(buf: &[u8]) -> Parsed {
+    let end = match buf.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => end + 4,
+        None => return Parsed::Incomplete(buf.len() + 1),
+    };
+    let line = match buf.iter().position(|&b| b == b'\r').map(|eol| str::from_utf8(&buf[..eol])) {
+        Some(Ok(line)) => line,
+        _ => return Parsed::Invalid,
+    };
+
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => Parsed::Done(path.to_string(), end),
+        _ => Parsed::Invalid,
+    }
+}
+
+fn metric(body: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    body.push_str(&format!("# HELP remcached_{} {}\n# TYPE remcached_{} {}\nremcached_{} {}\n", name, help, name, kind, name, value));
+}
+
+fn render<S: Store>(storage: &S, stats: &Stats) -> String {
+    let mut body = String::new();
+    metric(&mut body, "cmd_get_total", "counter", "Keys looked up by retrieval commands.", stats.cmd_get.get());
+    metric(&mut body, "cmd_set_total", "counter", "Storage commands received.", stats.cmd_set.get());
+    metric(&mut body, "get_hits_total", "counter", "Keys found by retrieval commands.", stats.get_hits.get());
+    metric(&mut body, "get_misses_total", "counter", "Keys not found by retrieval commands.", stats.get_misses.get());
+    metric(&mut body, "connections_total", "counter", "Connections accepted since startup.", stats.total_connections.get());
+    metric(&mut body, "rejected_connections_total", "counter", "Connections closed right after being accepted.", stats.rejected_connections.get());
+    metric(&mut body, "curr_connections", "gauge", "Open connections.", stats.curr_connections.get());
+    metric(&mut body, "read_bytes_total", "counter", "Bytes read from client sockets.", stats.bytes_read.get());
+    metric(&mut body, "written_bytes_total", "counter", "Bytes written to client sockets.", stats.bytes_written.get());
+    metric(&mut body, "curr_items", "gauge", "Items currently stored.", storage.len() as u64);
+    body
+}
+
+fn http_response(status: &str, content_type: &str, body: &str) -> Vec<u8> {
+    format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, content_type, body.len(), body).into_bytes()
+}
+
+pub fn handle<S: Store>(path: &str, storage: &S, stats: &Stats) -> Vec<u8> {
+    match path {
+        "/metrics" => http_response("200 OK", "text/plain; version=0.0.4", &render(storage, stats)),
+        _ => http_response("404 Not Found", "text/plain", "not found\n"),
+    }
+}
@@ -0,0 +1,287 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net as std_unix;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+use libc;
+use net2::TcpBuilder;
+use mio::{Evented, EventSet, PollOpt, Selector, Token};
+use mio::tcp::{TcpListener, TcpStream};
+use mio::unix::{UnixListener, UnixStream};
+
+use tls::TlsStream;
+
+// Where to listen: host:port for TCP, anything else is a unix socket path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Address, String> {
+        if s.is_empty() {
+            return Err("empty address".to_string());
+        }
+        if let Ok(addr) = s.parse() {
+            return Ok(Address::Tcp(addr));
+        }
+        if s.contains('/') {
+            return Ok(Address::Unix(PathBuf::from(s)));
+        }
+        Err("expected host:port or a socket path".to_string())
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Address {
+        Address::Tcp(addr)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Address::Tcp(ref addr) => write!(f, "{}", addr),
+            Address::Unix(ref path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    // the path is unlinked again when the listener goes away
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    // An IPv6 listener also takes IPv4 clients unless only_v6 is set, which
+    // it has to be for an IPv4 listener on the same port to bind at all.
+    // TCP listeners come with SO_REUSEADDR set, so a restart can bind again
+    // while connections of the previous run linger in TIME_WAIT.
+    // A unix socket given a mode is bound aside and only renamed into place
+    // once it has it, so there is no moment where the umask's permissions
+    // let anyone else connect.
+    pub fn bind(addr: &Address, backlog: usize, only_v6: bool, unix_mode: Option<u32>) -> io::Result<Listener> {
+        match *addr {
+            Address::Tcp(ref addr) => {
+                let builder = match *addr {
+                    SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+                    SocketAddr::V6(_) => {
+                        let builder = TcpBuilder::new_v6()?;
+                        set_option(builder.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, only_v6 as libc::c_int)?;
+                        builder
+                    }
+                };
+                builder.reuse_address(true)?;
+                builder.bind(addr)?;
+                let listener = builder.listen(clamp_backlog(backlog))?;
+                TcpListener::from_listener(listener, addr).map(Listener::Tcp)
+            }
+            Address::Unix(ref path) => {
+                remove_stale_socket(path)?;
+                let bound_at = match unix_mode {
+                    Some(_) => {
+                        let aside = aside(path);
+                        remove_stale_socket(&aside)?;
+                        aside
+                    }
+                    None => path.clone(),
+                };
+                // from here on dropping the listener unlinks whatever it is bound at
+                let mut listener = UnixListener::bind(&bound_at).map(|listener| Listener::Unix(listener, bound_at))?;
+                if let Listener::Unix(ref socket, ref mut bound_at) = listener {
+                    // listening again on a listening socket only changes its backlog
+                    if unsafe { libc::listen(socket.as_raw_fd(), clamp_backlog(backlog)) } < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    if let Some(mode) = unix_mode {
+                        fs::set_permissions(&*bound_at, fs::Permissions::from_mode(mode))?;
+                        fs::rename(&*bound_at, path)?;
+                        *bound_at = path.clone();
+                    }
+                }
+                Ok(listener)
+            }
+        }
+    }
+
+    // IPv6 addresses are kept to IPv6 when there is an IPv4 address to bind
+    // as well, a lone [::] serves both
+    pub fn bind_all(addrs: &[Address], backlog: usize, unix_mode: Option<u32>) -> io::Result<Vec<Listener>> {
+        let has_v4 = addrs.iter().any(|addr| matches!(*addr, Address::Tcp(SocketAddr::V4(_))));
+        addrs.iter().map(|addr| Listener::bind(addr, backlog, has_v4, unix_mode)).collect()
+    }
+
+    // With the client's address, unix clients are only told apart by the
+    // socket they came in on
+    pub fn accept(&self) -> io::Result<Option<(Stream, Address)>> {
+        match *self {
+            Listener::Tcp(ref listener) => Ok(listener.accept()?.map(|(socket, peer)| (Stream::Tcp(socket), Address::Tcp(peer)))),
+            Listener::Unix(ref listener, ref path) => Ok(listener.accept()?.map(|socket| (Stream::Unix(socket), Address::Unix(path.clone())))),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, ref path) = *self {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("failed to remove socket {}; err={:?}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn clamp_backlog(backlog: usize) -> libc::c_int {
+    backlog.min(libc::c_int::MAX as usize) as libc::c_int
+}
+
+fn set_option(fd: libc::c_int, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if unsafe { libc::setsockopt(fd, level, name, &value as *const libc::c_int as *const libc::c_void, len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Where a unix socket is bound before being renamed to its path, next to
+// it so the rename stays within one filesystem
+fn aside(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}", process::id()));
+    path.with_file_name(name)
+}
+
+// A socket file left behind by a previous run that nobody is listening on
+// any more would make bind fail with EADDRINUSE.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+    }
+    match std_unix::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use by another server", path.display()))),
+        Err(_) => {
+            info!("removing stale socket {}", path.display());
+            fs::remove_file(path)
+        }
+    }
+}
+
+impl Evented for Listener {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.register(selector, token, interest, opts),
+            Listener::Unix(ref listener, _) => listener.register(selector, token, interest, opts),
+        }
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.reregister(selector, token, interest, opts),
+            Listener::Unix(ref listener, _) => listener.reregister(selector, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.deregister(selector),
+            Listener::Unix(ref listener, _) => listener.deregister(selector),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(Box<TlsStream>),
+}
+
+impl Stream {
+    // TLS records still waiting for the socket to become writable
+    pub fn wants_write(&self) -> bool {
+        match *self {
+            Stream::Tls(ref stream) => stream.wants_write(),
+            _ => false,
+        }
+    }
+
+    pub fn flush_pending(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tls(ref mut stream) => stream.flush_records(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut socket) => socket.read(buf),
+            Stream::Unix(ref mut socket) => socket.read(buf),
+            Stream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut socket) => socket.write(buf),
+            Stream::Unix(ref mut socket) => socket.write(buf),
+            Stream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut socket) => socket.flush(),
+            Stream::Unix(ref mut socket) => socket.flush(),
+            Stream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl Evented for Stream {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref socket) => socket.register(selector, token, interest, opts),
+            Stream::Unix(ref socket) => socket.register(selector, token, interest, opts),
+            Stream::Tls(ref stream) => stream.socket().register(selector, token, interest, opts),
+        }
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref socket) => socket.reregister(selector, token, interest, opts),
+            Stream::Unix(ref socket) => socket.reregister(selector, token, interest, opts),
+            Stream::Tls(ref stream) => stream.socket().reregister(selector, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref socket) => socket.deregister(selector),
+            Stream::Unix(ref socket) => socket.deregister(selector),
+            Stream::Tls(ref stream) => stream.socket().deregister(selector),
+        }
+    }
+}
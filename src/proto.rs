@@ -1,63 +1,1219 @@
+use std::borrow::Cow;
+use std::io::Write;
 use std::str;
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use nom::*;
 
-#[derive(Debug, PartialEq, Eq)]
+use compress;
+use net::Address;
+use storage::{Shard, Store};
+use stats::Stats;
+
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 enum Method {
     GET,
     SET,
+    DELETE,
+    ADD,
+    REPLACE,
+    INCR,
+    DECR,
+    APPEND,
+    PREPEND,
+    GETS,
+    CAS,
+    QUIT,
+    VERSION,
+    FLUSH_ALL,
+    STATS,
+    CACHE_MEMLIMIT,
+    VERBOSITY,
+    WATCH,
+    PING,
+    LRU_CRAWLER,
+    TOUCH,
+    GAT,
+    GATS,
+}
+
+impl Method {
+    // Commands followed by <flags> <exptime> <bytes> and a data block
+    fn is_storage(&self) -> bool {
+        matches!(*self, Method::SET | Method::ADD | Method::REPLACE | Method::APPEND | Method::PREPEND | Method::CAS)
+    }
+
+    fn is_retrieval(&self) -> bool {
+        matches!(*self, Method::GET | Method::GETS | Method::GAT | Method::GATS)
+    }
+
+    // get and touch, the new <exptime> comes before the keys
+    fn is_touching(&self) -> bool {
+        matches!(*self, Method::GAT | Method::GATS)
+    }
+
+    // Everything that changes what is stored, including expiry times
+    fn is_mutation(&self) -> bool {
+        self.is_storage() || matches!(*self, Method::DELETE | Method::INCR | Method::DECR | Method::TOUCH | Method::GAT | Method::GATS | Method::FLUSH_ALL)
+    }
+
+    fn returns_cas(&self) -> bool {
+        matches!(*self, Method::GETS | Method::GATS)
+    }
+
+    fn has_key(&self) -> bool {
+        !matches!(*self, Method::QUIT | Method::VERSION | Method::FLUSH_ALL | Method::STATS | Method::CACHE_MEMLIMIT | Method::VERBOSITY | Method::WATCH | Method::PING | Method::LRU_CRAWLER)
+    }
+}
+
+// What `stats <group>` asks for, plain `stats` is the general group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsGroup {
+    General,
+    Settings,
+    Items,
+    Slabs,
+    Conns,
+    // not a group, zeroes the counters
+    Reset,
 }
 
 #[derive(Debug)]
 pub struct Request {
     command: Method,
-    key: String,
-    rest: String,
-    body: String,
+    key: Vec<u8>,
+    keys: Vec<Vec<u8>>,
+    flags: u32,
+    exptime: i64,
+    // None when the delta isn't a number that fits in 64 bits
+    delta: Option<u64>,
+    cas_unique: u64,
+    // flush_all delay, or the legacy hold time of delete
+    delay: u64,
+    // new memory limit in megabytes for cache_memlimit
+    memlimit: u64,
+    verbosity: u64,
+    stats: StatsGroup,
+    noreply: bool,
+    // length of the data block announced by a storage command
+    bytes: usize,
+    body: Vec<u8>,
+    // set in its short form, the value was on the command line and the
+    // flags and exptime are up to the server
+    inline: bool,
 }
 
 named!(parse_command<&[u8], Method>,
     alt!(
+        map!(tag!("gets"), |_| Method::GETS) |
+        map!(tag!("gats"), |_| Method::GATS) |
+        map!(tag!("gat"), |_| Method::GAT) |
         map!(tag!("get"), |_| Method::GET) |
-        map!(tag!("set"), |_| Method::SET)
+        map!(tag!("set"), |_| Method::SET) |
+        map!(tag!("delete"), |_| Method::DELETE) |
+        map!(tag!("add"), |_| Method::ADD) |
+        map!(tag!("replace"), |_| Method::REPLACE) |
+        map!(tag!("incr"), |_| Method::INCR) |
+        map!(tag!("decr"), |_| Method::DECR) |
+        map!(tag!("append"), |_| Method::APPEND) |
+        map!(tag!("prepend"), |_| Method::PREPEND) |
+        map!(tag!("cache_memlimit"), |_| Method::CACHE_MEMLIMIT) |
+        map!(tag!("cas"), |_| Method::CAS) |
+        map!(tag!("quit"), |_| Method::QUIT) |
+        map!(tag!("version"), |_| Method::VERSION) |
+        map!(tag!("verbosity"), |_| Method::VERBOSITY) |
+        map!(tag!("watch"), |_| Method::WATCH) |
+        map!(tag!("ping"), |_| Method::PING) |
+        map!(tag!("lru_crawler"), |_| Method::LRU_CRAWLER) |
+        map!(tag!("flush_all"), |_| Method::FLUSH_ALL) |
+        map!(tag!("stats"), |_| Method::STATS) |
+        map!(tag!("touch"), |_| Method::TOUCH)
     )
 );
 
-named!(pub parse_request<&[u8], Request>, ws!(do_parse!(
+// The end of the command line, trailing spaces are fine but nothing else.
+// Whether a bare \n was enough is up to parse().
+named!(parse_line<&[u8], &[u8]>,
+    recognize!(tuple!(take_while!(|c| c == b' '), opt!(tag!("\r")), tag!("\n")))
+);
+
+named!(parse_u32<&[u8], u32>,
+    map_res!(map_res!(digit, str::from_utf8), str::parse)
+);
+
+named!(parse_i64<&[u8], i64>,
+    map_res!(map_res!(recognize!(pair!(opt!(tag!("-")), digit)), str::from_utf8), str::parse)
+);
+
+named!(parse_u64<&[u8], u64>,
+    map_res!(map_res!(digit, str::from_utf8), str::parse)
+);
+
+named!(parse_usize<&[u8], usize>,
+    map_res!(map_res!(digit, str::from_utf8), str::parse)
+);
+
+// Only plain digits, no sign or spaces, and at most u64::MAX
+fn parse_number(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    str::from_utf8(digits).ok()?.parse().ok()
+}
+
+// <flags> <exptime> <bytes>
+named!(parse_storage<&[u8], (u32, i64, usize)>, do_parse!(
+    tag!(" ") >>
+    flags: parse_u32 >>
+    tag!(" ") >>
+    exptime: parse_i64 >>
+    tag!(" ") >>
+    bytes: parse_usize >>
+    ((flags, exptime, bytes))
+));
+
+const MAX_KEY_LENGTH: usize = 250;
+
+// Keys are at most 250 bytes without whitespace or control characters
+pub fn valid_key(key: &[u8]) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LENGTH && !key.iter().any(|&b| b <= b' ' || b == 0x7f)
+}
+
+fn to_keys(keys: Vec<&[u8]>) -> Result<Vec<&[u8]>, ()> {
+    if keys.iter().all(|key| valid_key(key)) {
+        Ok(keys)
+    } else {
+        Err(())
+    }
+}
+
+fn to_stats_group(group: Option<&[u8]>) -> Result<StatsGroup, ()> {
+    match group {
+        None => Ok(StatsGroup::General),
+        Some(b"settings") => Ok(StatsGroup::Settings),
+        Some(b"items") => Ok(StatsGroup::Items),
+        Some(b"slabs") => Ok(StatsGroup::Slabs),
+        Some(b"conns") => Ok(StatsGroup::Conns),
+        Some(b"reset") => Ok(StatsGroup::Reset),
+        Some(_) => Err(()),
+    }
+}
+
+// Keys are raw bytes, they don't have to be UTF-8
+named!(parse_key<&[u8], &[u8]>,
+    verify!(is_not!(" \r\n"), valid_key)
+);
+
+// an invalid key anywhere fails the whole line rather than ending the list
+named!(parse_more_keys<&[u8], Vec<&[u8]>>,
+    map_res!(many0!(preceded!(tag!(" "), is_not!(" \r\n"))), to_keys)
+);
+
+// `set <key> <value>`, the short form some minimal clients send, with the
+// value on the command line and no flags, exptime or length. A value is a
+// single word, anything more has to be the usual form.
+named!(parse_inline_value<&[u8], &[u8]>,
+    terminated!(preceded!(tag!(" "), is_not!(" \r\n")), peek!(parse_line))
+);
+
+// What the storage arguments come to for a value on the command line
+fn inline_storage(value: &[u8]) -> (u32, i64, usize, Option<&[u8]>) {
+    (0, 0, value.len(), Some(value))
+}
+
+// The command line only, the data block of storage commands is taken by
+// length in parse()
+named!(pub parse_request<&[u8], Request>, do_parse!(
     command: parse_command >>
-    key: map_res!(take_until!(" "), str::from_utf8) >>
-    rest: map_res!(take_until!("\n"), str::from_utf8) >>
-    body_r: cond!(command == Method::SET, map_res!(take_until!("\n"), str::from_utf8)) >>
-    (Request { 
-        command: command,
-        key: key.into(),
-        rest: rest.into(),
-        body: match body_r {
-            Some(body) => body.into(),
-            None => String::new(),
-        },
+    gat_exptime: cond_with_error!(command.is_touching(), preceded!(tag!(" "), parse_i64)) >>
+    key: cond_with_error!(command.has_key(), preceded!(tag!(" "), parse_key)) >>
+    more_keys: cond_with_error!(command.is_retrieval(), parse_more_keys) >>
+    storage: cond_with_error!(command.is_storage(), alt!(
+        parse_storage => { |(flags, exptime, bytes)| (flags, exptime, bytes, None) } |
+        cond_reduce!(command == Method::SET, parse_inline_value) => { inline_storage }
+    )) >>
+    cas_unique: cond_with_error!(command == Method::CAS, preceded!(tag!(" "), parse_u64)) >>
+    delta: cond_with_error!(command == Method::INCR || command == Method::DECR, preceded!(tag!(" "), map!(is_not!(" \r\n"), parse_number))) >>
+    touch_exptime: cond_with_error!(command == Method::TOUCH, preceded!(tag!(" "), parse_i64)) >>
+    delay: cond_with_error!(command == Method::FLUSH_ALL || command == Method::DELETE, opt!(preceded!(tag!(" "), parse_u64))) >>
+    memlimit: cond_with_error!(command == Method::CACHE_MEMLIMIT, preceded!(tag!(" "), parse_u64)) >>
+    verbosity: cond_with_error!(command == Method::VERBOSITY, preceded!(tag!(" "), parse_u64)) >>
+    // fetch events are the only kind there is so far
+    cond_with_error!(command == Method::WATCH, opt!(tag!(" fetchers"))) >>
+    // metadump is the only crawler command, and there is only class 1
+    cond_with_error!(command == Method::LRU_CRAWLER, alt!(tag!(" metadump all") | tag!(" metadump 1"))) >>
+    stats: cond_with_error!(command == Method::STATS, map_res!(opt!(preceded!(tag!(" "), is_not!(" \r\n"))), to_stats_group)) >>
+    noreply: opt!(tag!(" noreply")) >>
+    parse_line >>
+    ({
+        let (flags, exptime, bytes, inline) = storage.unwrap_or((0, 0, 0, None));
+        Request {
+            command,
+            key: key.unwrap_or_default().to_vec(),
+            keys: key.into_iter().chain(more_keys.unwrap_or_default()).map(<[u8]>::to_vec).collect(),
+            flags,
+            exptime: touch_exptime.or(gat_exptime).unwrap_or(exptime),
+            delta: delta.unwrap_or_default(),
+            cas_unique: cas_unique.unwrap_or(0),
+            delay: delay.unwrap_or_default().unwrap_or(0),
+            memlimit: memlimit.unwrap_or(0),
+            verbosity: verbosity.unwrap_or(0),
+            stats: stats.unwrap_or(StatsGroup::General),
+            noreply: noreply.is_some(),
+            bytes,
+            body: inline.map(<[u8]>::to_vec).unwrap_or_default(),
+            inline: inline.is_some(),
+        }
     })
-)));
+));
+
+impl Request {
+    pub fn is_quit(&self) -> bool {
+        self.command == Method::QUIT
+    }
+
+    // A flush_all that goes ahead, now or after its delay
+    pub fn is_flush(&self) -> bool {
+        self.command == Method::FLUSH_ALL && self.delay <= MAX_FLUSH_DELAY
+    }
+
+    pub fn is_watch(&self) -> bool {
+        self.command == Method::WATCH
+    }
+
+    pub fn is_metadump(&self) -> bool {
+        self.command == Method::LRU_CRAWLER
+    }
+
+    // The keys a retrieval command looks up, for publishing fetch events
+    pub fn fetched_keys(&self) -> Option<Vec<Vec<u8>>> {
+        match self.command {
+            command if command.is_retrieval() => Some(self.keys.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn stats_group(&self) -> Option<StatsGroup> {
+        match self.command {
+            Method::STATS => Some(self.stats),
+            _ => None,
+        }
+    }
+
+    // The short form of set leaves the flags and exptime to the server
+    pub fn fill_defaults(&mut self, flags: u32, exptime: i64) {
+        if self.inline {
+            self.flags = flags;
+            self.exptime = exptime;
+        }
+    }
+
+    // Delayed flush_all is carried out by the event loop timer
+    pub fn flush_delay(&self) -> Option<u64> {
+        match self.command {
+            Method::FLUSH_ALL if self.delay > 0 => Some(self.delay),
+            _ => None,
+        }
+    }
+}
+
+// exptime values above this many seconds (30 days) are absolute unix timestamps
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+// the longest flush_all delay, in seconds
+const MAX_FLUSH_DELAY: u64 = MAX_RELATIVE_EXPTIME as u64;
+
+#[derive(Debug)]
+pub struct Item {
+    value: Vec<u8>,
+    // the length the value decompresses to, if it is stored compressed
+    original_len: Option<usize>,
+    flags: u32,
+    expires: Option<Instant>,
+    cas: u64,
+}
+
+impl Item {
+    pub fn new(value: Vec<u8>, flags: u32, exptime: i64, cas: u64) -> Item {
+        Item {
+            value,
+            original_len: None,
+            flags,
+            expires: deadline(exptime),
+            cas,
+        }
+    }
+
+    // The value as the client sent it, whether or not it is stored compressed.
+    // compress() only keeps a form it has decompressed once already, so the
+    // error can't happen short of memory corruption, and isn't worth taking
+    // the event loop down for.
+    pub fn value(&self) -> Cow<'_, [u8]> {
+        match self.original_len {
+            Some(len) => match compress::decompress(&self.value, len) {
+                Some(value) => Cow::Owned(value),
+                None => {
+                    error!("stored value of {} bytes does not decompress", len);
+                    Cow::Borrowed(&[])
+                }
+            },
+            None => Cow::Borrowed(&self.value),
+        }
+    }
+
+    pub fn value_len(&self) -> usize {
+        self.original_len.unwrap_or(self.value.len())
+    }
+
+    // The bytes actually held, which is less than value_len when compressed
+    pub fn stored_len(&self) -> usize {
+        self.value.len()
+    }
+
+    // Changing the value in place goes through its uncompressed form
+    fn value_mut(&mut self) -> &mut Vec<u8> {
+        if self.original_len.is_some() {
+            self.value = self.value().into_owned();
+            self.original_len = None;
+        }
+        &mut self.value
+    }
+
+    // Values that don't get smaller, such as already compressed data, are
+    // kept as they are, and so is any value the codec fails to round-trip
+    pub fn compress(&mut self) {
+        if self.original_len.is_some() {
+            return;
+        }
+        let compressed = compress::compress(&self.value);
+        if compressed.len() < self.value.len() && compress::decompress(&compressed, self.value.len()).as_deref() == Some(&self.value[..]) {
+            self.original_len = Some(self.value.len());
+            self.value = compressed;
+        }
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn cas(&self) -> u64 {
+        self.cas
+    }
+
+    // The expiry as an absolute unix timestamp, 0 for never, which Item::new
+    // takes back as is
+    pub fn exptime(&self) -> i64 {
+        let expires = match self.expires {
+            Some(expires) => expires,
+            None => return 0,
+        };
+        let remaining = expires.saturating_duration_since(Instant::now());
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        (unix_now + remaining).as_secs_f64().ceil() as i64
+    }
+
+    pub fn append(&mut self, data: &[u8], cas: u64) {
+        self.value_mut().extend(data);
+        self.cas = cas;
+    }
+
+    pub fn prepend(&mut self, data: &[u8], cas: u64) {
+        self.value_mut().splice(0..0, data.iter().cloned());
+        self.cas = cas;
+    }
+
+    // Seconds left to live, -1 for never, as meta commands report it
+    pub fn ttl(&self) -> i64 {
+        match self.expires {
+            Some(expires) => expires.saturating_duration_since(Instant::now()).as_secs() as i64,
+            None => -1,
+        }
+    }
+
+    pub fn touch(&mut self, exptime: i64) {
+        self.expires = deadline(exptime);
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expires {
+            Some(expires) => expires <= Instant::now(),
+            None => false,
+        }
+    }
+}
+
+fn deadline(exptime: i64) -> Option<Instant> {
+    let now = Instant::now();
+    match exptime {
+        0 => None,
+        e if e < 0 => Some(now),
+        e if e <= MAX_RELATIVE_EXPTIME => Some(now + Duration::from_secs(e as u64)),
+        e => {
+            let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            Some(now + Duration::from_secs((e as u64).saturating_sub(unix_now)))
+        },
+    }
+}
+
+pub fn next_cas(cas_counter: &AtomicU64) -> u64 {
+    cas_counter.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+// The hits of a retrieval, whose reply handle writes in full: each VALUE
+// block goes straight into it while the item's shard is locked, so a value
+// is copied once, from the store into the reply, and END follows the last.
+// Nothing of it is left to encode.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Values {
+    // key and value length of each hit, in order
+    hits: Vec<(Vec<u8>, usize)>,
+}
+
+impl Values {
+    // the cas is only given for gets
+    fn push(&mut self, reply: &mut Vec<u8>, key: Vec<u8>, item: &Item, cas: Option<u64>) {
+        let value = item.value();
+        reply.reserve(VALUE_OVERHEAD + key.len() + value.len());
+        reply.extend_from_slice(b"VALUE ");
+        reply.extend_from_slice(&key);
+        write!(reply, " {} {}", item.flags, value.len()).expect("writing to a Vec");
+        if let Some(cas) = cas {
+            write!(reply, " {}", cas).expect("writing to a Vec");
+        }
+        reply.extend_from_slice(b"\r\n");
+        reply.extend_from_slice(&value);
+        reply.extend_from_slice(b"\r\n");
+        self.hits.push((key, value.len()));
+    }
+
+    // the value length of a key that was hit
+    pub fn size(&self, key: &[u8]) -> Option<usize> {
+        self.hits.iter().find(|hit| hit.0 == key).map(|hit| hit.1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hits.is_empty()
+    }
+}
+
+// What a command did, independent of how it goes out on the wire
+#[derive(Debug, PartialEq, Eq)]
+pub enum Response {
+    // quit and noreply commands send nothing back
+    Nothing,
+    Stored,
+    NotStored,
+    Exists,
+    NotFound,
+    Deleted,
+    Touched,
+    Ok,
+    Values(Values),
+    Number(u64),
+    Stats(Vec<(String, String)>),
+    Version(String),
+    Pong,
+    Reset,
+    // the command itself wasn't understood
+    Error,
+    // the same, naming the verb for client developers
+    UnknownCommand(String),
+    ClientError(String),
+    ServerError(String),
+}
+
+pub fn stat<V: ToString>(name: &str, value: V) -> (String, String) {
+    (name.to_string(), value.to_string())
+}
+
+// There is no slab allocator, everything is reported as a single class 1.
+// Classes without items are left out, like memcached does. Pages and chunks
+// don't exist here, so their counters are left out as well rather than made
+// up.
+const SLAB_CLASS: u32 = 1;
+
+fn item_stats<S: Store>(storage: &S) -> Vec<(String, String)> {
+    let items = storage.len();
+    if items == 0 {
+        return Vec::new();
+    }
+    let age = storage.oldest_age().unwrap_or_default().as_secs();
+    vec![
+        stat(&format!("items:{}:number", SLAB_CLASS), items),
+        stat(&format!("items:{}:age", SLAB_CLASS), age),
+        stat(&format!("items:{}:evicted", SLAB_CLASS), storage.evictions()),
+    ]
+}
+
+// key=<key> exp=<unix time or -1> la=<unix time> cas=<n> fetch=<yes|no> cls=1 size=<n>
+pub fn metadump_line(key: &[u8], item: &Item, idle: Duration, fetches: u64) -> Vec<u8> {
+    let exp = match item.exptime() {
+        0 => -1,
+        exptime => exptime,
+    };
+    let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let last_access = unix_now.saturating_sub(idle).as_secs();
+    let mut line = b"key=".to_vec();
+    line.extend(key);
+    line.extend(format!(" exp={} la={} cas={} fetch={} cls={} size={}\r\n",
+                        exp, last_access, item.cas(), if fetches > 0 { "yes" } else { "no" }, SLAB_CLASS, key.len() + item.stored_len()).into_bytes());
+    line
+}
+
+fn slab_stats<S: Store>(storage: &S, stats: &Stats) -> Vec<(String, String)> {
+    let items = storage.len();
+    let bytes = storage.bytes();
+    if items == 0 {
+        return vec![stat("active_slabs", 0), stat("total_malloced", 0)];
+    }
+    vec![
+        stat(&format!("{}:mem_requested", SLAB_CLASS), bytes),
+        stat(&format!("{}:get_hits", SLAB_CLASS), stats.get_hits.get()),
+        stat(&format!("{}:cmd_set", SLAB_CLASS), stats.cmd_set.get()),
+        stat("active_slabs", 1),
+        stat("total_malloced", bytes),
+    ]
+}
+
+pub fn encode_text(response: &Response) -> Vec<u8> {
+    match *response {
+        Response::Nothing => Vec::new(),
+        Response::Stored => b"STORED\r\n".to_vec(),
+        Response::NotStored => b"NOT_STORED\r\n".to_vec(),
+        Response::Exists => b"EXISTS\r\n".to_vec(),
+        Response::NotFound => b"NOT_FOUND\r\n".to_vec(),
+        Response::Deleted => b"DELETED\r\n".to_vec(),
+        Response::Touched => b"TOUCHED\r\n".to_vec(),
+        Response::Ok => b"OK\r\n".to_vec(),
+        // written to the reply by handle, END included
+        Response::Values(_) => Vec::new(),
+        Response::Number(number) => format!("{}\r\n", number).into_bytes(),
+        Response::Stats(ref stats) => {
+            let mut buf = Vec::new();
+            for (name, value) in stats {
+                buf.extend(format!("STAT {} {}\r\n", name, value).into_bytes());
+            }
+            buf.extend(b"END\r\n");
+            buf
+        },
+        Response::Version(ref version) => format!("VERSION {}\r\n", version).into_bytes(),
+        Response::Pong => b"PONG\r\n".to_vec(),
+        Response::Reset => b"RESET\r\n".to_vec(),
+        Response::Error => b"ERROR\r\n".to_vec(),
+        Response::UnknownCommand(ref verb) => format!("ERROR unknown command '{}'\r\n", verb).into_bytes(),
+        Response::ClientError(ref message) => format!("CLIENT_ERROR {}\r\n", message).into_bytes(),
+        Response::ServerError(ref message) => format!("SERVER_ERROR {}\r\n", message).into_bytes(),
+    }
+}
+
+// Appends to a reply that is already being put together
+pub fn encode_text_into(response: &Response, buf: &mut Vec<u8>) {
+    buf.extend(encode_text(response));
+}
+
+// "VALUE", flags, length and cas at their longest, and both line ends
+const VALUE_OVERHEAD: usize = 64;
+
+impl Response {
+    // short outcome for the command log
+    fn outcome(&self, keys: usize) -> &'static str {
+        match *self {
+            Response::Nothing => "none",
+            Response::Stored => "stored",
+            Response::NotStored => "not_stored",
+            Response::Exists => "exists",
+            Response::NotFound => "not_found",
+            Response::Deleted => "deleted",
+            Response::Touched => "touched",
+            Response::Ok => "ok",
+            Response::Values(ref values) if values.is_empty() => "miss",
+            Response::Values(ref values) if values.len() < keys => "partial",
+            Response::Values(_) => "hit",
+            Response::Number(_) => "number",
+            Response::Stats(_) => "stats",
+            Response::Version(_) => "version",
+            Response::Pong => "pong",
+            Response::Reset => "reset",
+            Response::Error | Response::UnknownCommand(_) => "error",
+            Response::ClientError(_) => "client_error",
+            Response::ServerError(_) => "server_error",
+        }
+    }
+}
+
+// The client's address only goes into the command log. Retrievals write
+// their VALUE blocks to the reply as they go, the response is encoded after
+// them.
+pub fn handle<S: Store>(command: Request, client: &Address, storage: &S, cas_counter: &AtomicU64, stats: &Stats, read_only: bool, reply: &mut Vec<u8>) -> Response {
+    let noreply = command.noreply;
+    let response = if log_enabled!(::log::Level::Info) {
+        execute_logged(command, client, storage, cas_counter, stats, read_only, reply)
+    } else {
+        execute(command, storage, cas_counter, stats, read_only, reply)
+    };
+    if noreply {
+        return Response::Nothing;
+    }
+    response
+}
+
+fn execute_logged<S: Store>(command: Request, client: &Address, storage: &S, cas_counter: &AtomicU64, stats: &Stats, read_only: bool, reply: &mut Vec<u8>) -> Response {
+    let started = Instant::now();
+    let method = command.command;
+    let keys = String::from_utf8_lossy(&command.keys.join(&b',')).into_owned();
+    let key_count = command.keys.len();
+    // values can be large or sensitive, only their size is logged
+    let bytes = command.body.len();
+    let response = execute(command, storage, cas_counter, stats, read_only, reply);
+    info!("command client={} method={:?} key={} bytes={} result={} latency_us={}",
+          client, method, keys, bytes, response.outcome(key_count), started.elapsed().as_micros());
+    response
+}
+
+// Turns down a storage command whose value can't be kept no matter what it
+// is, which the command line alone tells
+fn refuse_storage<S: Store>(command: &Request, storage: &S) -> Option<Response> {
+    if command.bytes > storage.max_item_size() {
+        return Some(Response::ServerError("object too large for cache".to_string()));
+    }
+    let shard = storage.shard(&command.key);
+    // append and prepend add to the value already there
+    let existing = match command.command {
+        Method::APPEND | Method::PREPEND => shard.peek(&command.key).map_or(0, |item| item.value_len()),
+        _ => 0,
+    };
+    if !shard.can_hold(&command.key, existing + command.bytes) {
+        return Some(Response::ServerError("out of memory storing object".to_string()));
+    }
+    None
+}
+
+// The reply to a storage command refused before its data block is read, so
+// none of it has to be buffered, or None if it has to be read after all
+pub fn refuse_header<S: Store>(command: &Request, storage: &S, stats: &Stats, read_only: bool) -> Option<Response> {
+    let response = if read_only {
+        Response::ServerError("read-only".to_string())
+    } else {
+        let response = refuse_storage(command, storage)?;
+        stats.cmd_set.incr();
+        response
+    };
+    debug!("refusing {:?} before its data block; response={:?}", command.command, response);
+    if command.noreply {
+        return Some(Response::Nothing);
+    }
+    Some(response)
+}
+
+fn execute<S: Store>(command: Request, storage: &S, cas_counter: &AtomicU64, stats: &Stats, read_only: bool, reply: &mut Vec<u8>) -> Response {
+    if read_only && command.command.is_mutation() {
+        return Response::ServerError("read-only".to_string());
+    }
+    if command.command.is_storage() {
+        stats.cmd_set.incr();
+        if let Some(response) = refuse_storage(&command, storage) {
+            return response;
+        }
+    }
 
-pub fn handle(command: Request, storage: &mut HashMap<String, String>) -> Vec<u8> {
     match command.command {
-        Method::GET => {
-            match storage.get(command.key.as_str()) {
-                Some(response)=> return response.clone().into_bytes(),
-                None=> {},
-            };
+        Method::GET | Method::GETS | Method::GAT | Method::GATS => {
+            let mut values = Values { hits: Vec::with_capacity(command.keys.len()) };
+            for key in command.keys {
+                stats.cmd_get.incr();
+                let mut shard = storage.shard(&key);
+                let item = shard.fetch(&key);
+                if item.is_some() {
+                    stats.get_hits.incr();
+                } else {
+                    stats.get_misses.incr();
+                }
+                if let Some(item) = item {
+                    if command.command.is_touching() {
+                        item.touch(command.exptime);
+                    }
+                    values.push(reply, key, item, if command.command.returns_cas() { Some(item.cas) } else { None });
+                }
+            }
+            reply.extend_from_slice(b"END\r\n");
+            Response::Values(values)
         },
         Method::SET => {
-            storage.insert(command.key, command.body);
+            let cas = next_cas(cas_counter);
+            storage.shard(&command.key).set(command.key, Item::new(command.body, command.flags, command.exptime, cas));
+            Response::Stored
+        },
+        Method::ADD => {
+            let mut shard = storage.shard(&command.key);
+            if shard.get(&command.key).is_some() {
+                return Response::NotStored;
+            }
+            let cas = next_cas(cas_counter);
+            shard.set(command.key, Item::new(command.body, command.flags, command.exptime, cas));
+            Response::Stored
+        },
+        Method::REPLACE => {
+            let mut shard = storage.shard(&command.key);
+            if shard.get(&command.key).is_none() {
+                return Response::NotStored;
+            }
+            let cas = next_cas(cas_counter);
+            shard.set(command.key, Item::new(command.body, command.flags, command.exptime, cas));
+            Response::Stored
+        },
+        // the item being extended keeps its flags and expiry
+        Method::APPEND | Method::PREPEND => {
+            let mut shard = storage.shard(&command.key);
+            match shard.get(&command.key) {
+                Some(item) if command.command == Method::APPEND => item.append(&command.body, next_cas(cas_counter)),
+                Some(item) => item.prepend(&command.body, next_cas(cas_counter)),
+                None => return Response::NotStored,
+            }
+            shard.resize(&command.key);
+            Response::Stored
+        },
+        Method::INCR | Method::DECR => {
+            // a bad delta is the client's mistake whether or not the key is there
+            let delta = match command.delta {
+                Some(delta) => delta,
+                None => return Response::ClientError("invalid numeric delta argument".to_string()),
+            };
+            let mut shard = storage.shard(&command.key);
+            let item = match shard.get(&command.key) {
+                Some(item) => item,
+                None => return Response::NotFound,
+            };
+            let current = match parse_number(&item.value()) {
+                Some(current) => current,
+                None => return Response::ClientError("cannot increment or decrement non-numeric value".to_string()),
+            };
+            // incr wraps around at 64 bits, decr stops at zero, and the
+            // result is stored as its plain decimal digits
+            let updated = match command.command {
+                Method::INCR => current.wrapping_add(delta),
+                _ => current.saturating_sub(delta),
+            };
+            *item.value_mut() = updated.to_string().into_bytes();
+            item.cas = next_cas(cas_counter);
+            shard.resize(&command.key);
+            Response::Number(updated)
+        },
+        Method::CAS => {
+            let mut shard = storage.shard(&command.key);
+            match shard.get(&command.key) {
+                Some(item) if item.cas != command.cas_unique => Response::Exists,
+                Some(_) => {
+                    let cas = next_cas(cas_counter);
+                    shard.set(command.key, Item::new(command.body, command.flags, command.exptime, cas));
+                    Response::Stored
+                },
+                None => Response::NotFound,
+            }
+        },
+        Method::QUIT => Response::Nothing,
+        Method::FLUSH_ALL => {
+            if command.delay > MAX_FLUSH_DELAY {
+                return Response::ClientError("invalid exptime argument".to_string());
+            }
+            if command.delay == 0 {
+                storage.clear();
+            }
+            Response::Ok
+        },
+        Method::STATS => match command.stats {
+            StatsGroup::General => Response::Stats(vec![
+                stat("cmd_get", stats.cmd_get.get()),
+                stat("cmd_set", stats.cmd_set.get()),
+                stat("get_hits", stats.get_hits.get()),
+                stat("get_misses", stats.get_misses.get()),
+                stat("curr_items", storage.len()),
+                stat("bytes", storage.bytes()),
+                stat("limit_maxbytes", storage.memory_limit()),
+                stat("total_connections", stats.total_connections.get()),
+                stat("rejected_connections", stats.rejected_connections.get()),
+                stat("bytes_read", stats.bytes_read.get()),
+                stat("bytes_written", stats.bytes_written.get()),
+            ]),
+            StatsGroup::Items => Response::Stats(item_stats(storage)),
+            StatsGroup::Slabs => Response::Stats(slab_stats(storage, stats)),
+            // the gauges, like curr_items and bytes, keep their values
+            StatsGroup::Reset => {
+                stats.reset();
+                storage.reset_evictions();
+                Response::Reset
+            },
+            // answered by the server, which knows its config and connections
+            StatsGroup::Settings | StatsGroup::Conns => Response::ServerError("stats group not available here".to_string()),
+        },
+        Method::CACHE_MEMLIMIT => {
+            storage.set_memory_limit((command.memlimit as usize).saturating_mul(1024 * 1024));
+            Response::Ok
+        },
+        Method::VERBOSITY => {
+            let level = match command.verbosity {
+                0 => ::log::LevelFilter::Error,
+                1 => ::log::LevelFilter::Warn,
+                2 => ::log::LevelFilter::Info,
+                _ => ::log::LevelFilter::Debug,
+            };
+            ::log::set_max_level(level);
+            Response::Ok
+        },
+        // the connection is turned into a log stream by the server
+        Method::WATCH => Response::Ok,
+        // streamed by the server, which walks the shards itself
+        Method::LRU_CRAWLER => Response::ServerError("metadump not available here".to_string()),
+        // a health check, storage and stats are left alone
+        Method::PING => Response::Pong,
+        Method::VERSION => Response::Version(env!("CARGO_PKG_VERSION").to_string()),
+        Method::TOUCH => {
+            match storage.shard(&command.key).get(&command.key) {
+                Some(item) => {
+                    item.touch(command.exptime);
+                    Response::Touched
+                },
+                None => Response::NotFound,
+            }
+        },
+        Method::DELETE => {
+            // memcached dropped delete with a hold time, only an explicit 0 is accepted
+            if command.delay != 0 {
+                return Response::ClientError("bad command line format. Usage: delete <key> [noreply]".to_string());
+            }
+            let mut shard = storage.shard(&command.key);
+            match shard.get(&command.key) {
+                Some(_) => {
+                    shard.remove(&command.key);
+                    Response::Deleted
+                },
+                None => Response::NotFound,
+            }
+        },
+    }
+}
+
+// Longest verb echoed back for an unknown command
+const MAX_ECHOED_VERB: usize = 32;
+
+#[derive(Debug)]
+pub enum ParseError {
+    // with the first word of the line
+    UnknownCommand(Vec<u8>),
+    BadFormat,
+    TooLarge,
+    BadDataChunk,
+    // the connection is closed after the reply
+    LineTooLong,
+}
+
+impl ParseError {
+    // Strict is the bare ERROR the protocol asks for, otherwise the unknown
+    // verb is echoed back with anything unprintable replaced
+    pub fn response(&self, strict: bool) -> Response {
+        match *self {
+            ParseError::UnknownCommand(_) if strict => Response::Error,
+            ParseError::UnknownCommand(ref verb) => Response::UnknownCommand(
+                verb.iter().take(MAX_ECHOED_VERB).map(|&b| if b.is_ascii_graphic() && b != b'\'' { b as char } else { '?' }).collect()),
+            ParseError::BadFormat => Response::ClientError("bad command line format".to_string()),
+            ParseError::TooLarge => Response::ServerError("command too large".to_string()),
+            ParseError::BadDataChunk => Response::ClientError("bad data chunk".to_string()),
+            ParseError::LineTooLong => Response::Error,
+        }
+    }
+
+    pub fn closes_connection(&self) -> bool {
+        matches!(*self, ParseError::LineTooLong)
+    }
+}
+
+pub enum Parsed {
+    // the request and the number of bytes it took up
+    Done(Request, usize),
+    // the buffer has to grow to at least this many bytes before parsing again
+    Incomplete(usize),
+    // the command line of a storage command whose data block has yet to
+    // arrive in full, and the bytes both take up together
+    Header(Request, usize),
+    // the offending line has to be skipped to get back in sync
+    Error(ParseError, usize),
+}
+
+// A command line this long, data block aside, is taken for abuse rather than
+// scanned any further for its end
+pub const MAX_LINE_LENGTH: usize = 8 * 1024;
+
+pub fn line_too_long(buf: &[u8]) -> bool {
+    buf.len() > MAX_LINE_LENGTH && !buf[..=MAX_LINE_LENGTH].contains(&b'\n')
+}
+
+// Lines end in \r\n as the protocol says, lenient also takes a bare \n
+pub fn parse(buf: &[u8], lenient: bool) -> Parsed {
+    if line_too_long(buf) {
+        return Parsed::Error(ParseError::LineTooLong, buf.len());
+    }
+    // Optional trailing arguments like noreply make the parser ask for more
+    // input when it runs out mid line, and the bytes it asks for may never
+    // come if the terminator is all that is left. A full line never runs out.
+    if !buf.contains(&b'\n') {
+        return Parsed::Incomplete(buf.len() + 1);
+    }
+    match parse_request(buf) {
+        IResult::Done(rest, command) => {
+            let line = buf.len() - rest.len();
+            if !lenient && !buf[..line].ends_with(b"\r\n") {
+                return Parsed::Error(ParseError::BadFormat, line);
+            }
+            if command.command.is_storage() && !command.inline {
+                parse_data(buf, line, command)
+            } else {
+                Parsed::Done(command, line)
+            }
+        },
+        IResult::Error(_er) => {
+            // wait for the whole line before giving up on it
+            let end = match buf.iter().position(|&b| b == b'\n') {
+                Some(end) => end,
+                None => return Parsed::Incomplete(buf.len() + 1),
+            };
+            let error = match parse_command(buf) {
+                IResult::Done(rest, _) if rest.starts_with(b" ") || rest.starts_with(b"\r") || rest.starts_with(b"\n") => ParseError::BadFormat,
+                _ => ParseError::UnknownCommand(buf[..end].split(|&b| b == b' ' || b == b'\r').next().unwrap_or_default().to_vec()),
+            };
+            Parsed::Error(error, end + 1)
         },
+        IResult::Incomplete(Needed::Size(needed)) => Parsed::Incomplete(needed),
+        IResult::Incomplete(Needed::Unknown) => Parsed::Incomplete(buf.len() + 1),
+    }
+}
+
+// <data block>\r\n following a command line of `line` bytes
+fn parse_data(buf: &[u8], line: usize, mut command: Request) -> Parsed {
+    let end = line.saturating_add(command.bytes);
+    if buf.len() < end.saturating_add(2) {
+        return Parsed::Header(command, end.saturating_add(2));
     }
-    return vec![111, 107, 10];
+    if &buf[end..end + 2] != b"\r\n" {
+        // the data block was longer than announced, skip the rest of it
+        return match buf[end..].iter().position(|&b| b == b'\n') {
+            Some(newline) => Parsed::Error(ParseError::BadDataChunk, end + newline + 1),
+            None => Parsed::Incomplete(buf.len() + 1),
+        };
+    }
+    command.body = buf[line..end].to_vec();
+    Parsed::Done(command, end + 2)
 }
 
-pub fn parse(buf: &[u8]) -> Option<Request> {
-    return match parse_request(buf) {
-        IResult::Done(_raw, command)=> Option::Some(command),
-        IResult::Error(_er)=> Option::None,
-        IResult::Incomplete(_ingore)=> Option::None,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::thread;
+    use storage::Storage;
+
+    // A cache to run commands against, keeping the cas counter between them
+    struct Cache {
+        storage: Storage,
+        cas_counter: AtomicU64,
+        stats: Stats,
+    }
+
+    impl Cache {
+        fn new() -> Cache {
+            Cache { storage: Storage::new(1024, 1024 * 1024, 64 << 20, 0), cas_counter: AtomicU64::new(0), stats: Stats::default() }
+        }
+
+        // What the command did, with the reply it got
+        fn call(&self, input: &[u8]) -> (Response, Vec<u8>) {
+            let command = match parse(input, false) {
+                Parsed::Done(command, consumed) if consumed == input.len() => command,
+                _ => panic!("not a single complete command: {:?}", String::from_utf8_lossy(input)),
+            };
+            let mut reply = Vec::new();
+            let response = handle(command, &Address::Unix(PathBuf::new()), &self.storage, &self.cas_counter, &self.stats, false, &mut reply);
+            encode_text_into(&response, &mut reply);
+            (response, reply)
+        }
+
+        fn run(&self, input: &[u8]) -> Response {
+            self.call(input).0
+        }
+
+        fn reply(&self, input: &[u8]) -> Vec<u8> {
+            self.call(input).1
+        }
+    }
+
+    #[test]
+    fn set_replies_stored() {
+        let cache = Cache::new();
+        assert_eq!(cache.reply(b"set key 0 0 5\r\nvalue\r\n"), b"STORED\r\n");
+        assert_eq!(cache.reply(b"get key\r\n"), b"VALUE key 0 5\r\nvalue\r\nEND\r\n");
+    }
+
+    #[test]
+    fn unknown_command_replies_error() {
+        match parse(b"bogus\r\n", false) {
+            Parsed::Error(error, 7) => assert_eq!(encode_text(&error.response(true)), b"ERROR\r\n"),
+            _ => panic!("bogus parsed"),
+        }
+    }
+
+    #[test]
+    fn data_block_may_hold_newlines() {
+        let cache = Cache::new();
+        assert_eq!(cache.reply(b"set key 0 0 12\r\nhello\nworld\n\r\n"), b"STORED\r\n");
+        assert_eq!(cache.reply(b"get key\r\n"), b"VALUE key 0 12\r\nhello\nworld\n\r\nEND\r\n");
+    }
+
+    #[test]
+    fn short_reads_wait_for_the_rest() {
+        let input = b"set key 0 0 11\r\nhello world\r\n";
+        // the command line alone
+        assert!(matches!(parse(&input[..10], false), Parsed::Incomplete(_)));
+        // part of the data block, the parser knows how much is still to come
+        for end in 16..input.len() {
+            match parse(&input[..end], false) {
+                Parsed::Header(_, len) => assert_eq!(len, input.len()),
+                _ => panic!("{} bytes parsed as more than a header", end),
+            }
+        }
+        match parse(input, false) {
+            Parsed::Done(command, consumed) => {
+                assert_eq!(consumed, input.len());
+                assert_eq!(command.body, b"hello world");
+            },
+            _ => panic!("the whole command didn't parse"),
+        }
+    }
+
+    #[test]
+    fn incr_wraps_around_at_64_bits() {
+        let cache = Cache::new();
+        let max = u64::MAX.to_string();
+        assert_eq!(cache.run(format!("set n 0 0 {}\r\n{}\r\n", max.len(), max).as_bytes()), Response::Stored);
+        assert_eq!(cache.run(b"incr n 1\r\n"), Response::Number(0));
+        assert_eq!(cache.run(b"incr n 5\r\n"), Response::Number(5));
+        assert_eq!(cache.run(b"decr n 10\r\n"), Response::Number(0));
+    }
+
+    #[test]
+    fn incr_refuses_what_is_not_a_number() {
+        let cache = Cache::new();
+        assert_eq!(cache.run(b"set word 0 0 5\r\nhello\r\n"), Response::Stored);
+        assert_eq!(cache.reply(b"incr word 1\r\n"), b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n");
+        assert_eq!(cache.reply(b"incr word x\r\n"), b"CLIENT_ERROR invalid numeric delta argument\r\n");
+        // the delta is checked before the key is looked up
+        assert_eq!(cache.reply(b"decr missing x\r\n"), b"CLIENT_ERROR invalid numeric delta argument\r\n");
+        assert_eq!(cache.run(b"decr missing 1\r\n"), Response::NotFound);
+    }
+
+    #[test]
+    fn append_and_prepend_keep_binary_values_intact() {
+        let cache = Cache::new();
+        assert_eq!(cache.run(b"set bin 0 0 2\r\n\xff\x00\r\n"), Response::Stored);
+        assert_eq!(cache.run(b"append bin 0 0 2\r\n\xc3\x28\r\n"), Response::Stored);
+        assert_eq!(cache.run(b"prepend bin 0 0 1\r\n\x80\r\n"), Response::Stored);
+        assert_eq!(cache.reply(b"get bin\r\n"), b"VALUE bin 0 5\r\n\x80\xff\x00\xc3\x28\r\nEND\r\n");
+        assert_eq!(cache.run(b"append missing 0 0 1\r\nx\r\n"), Response::NotStored);
+        assert_eq!(cache.run(b"prepend missing 0 0 1\r\nx\r\n"), Response::NotStored);
+    }
+
+    // The cas unique of a key as gets reports it
+    fn cas_of(cache: &Cache, key: &str) -> u64 {
+        let reply = String::from_utf8(cache.reply(format!("gets {}\r\n", key).as_bytes())).unwrap();
+        let header = reply.lines().next().filter(|line| line.starts_with("VALUE ")).unwrap_or_else(|| panic!("gets {} got {:?}", key, reply));
+        header.rsplit(' ').next().unwrap().parse().expect("gets without a cas")
+    }
+
+    #[test]
+    fn cas_with_a_stale_unique_is_refused() {
+        let cache = Cache::new();
+        assert_eq!(cache.run(b"set key 0 0 3\r\nold\r\n"), Response::Stored);
+        let stale = cas_of(&cache, "key");
+        assert_eq!(cache.run(b"set key 0 0 3\r\nnew\r\n"), Response::Stored);
+        assert_eq!(cache.run(format!("cas key 0 0 4 {}\r\nlost\r\n", stale).as_bytes()), Response::Exists);
+        assert_eq!(cache.reply(b"get key\r\n"), b"VALUE key 0 3\r\nnew\r\nEND\r\n");
+        let fresh = cas_of(&cache, "key");
+        assert_eq!(cache.run(format!("cas key 0 0 4 {}\r\nwins\r\n", fresh).as_bytes()), Response::Stored);
+    }
+
+    #[test]
+    fn data_block_of_the_wrong_length_is_a_bad_chunk() {
+        for input in [&b"set a 0 0 3\r\ntoolong\r\nget a\r\n"[..], b"set a 0 0 3\r\nabc\nget a\r\n"] {
+            let skipped = match parse(input, false) {
+                Parsed::Error(error, skipped) => {
+                    assert_eq!(encode_text(&error.response(true)), b"CLIENT_ERROR bad data chunk\r\n");
+                    skipped
+                },
+                _ => panic!("{:?} parsed", String::from_utf8_lossy(input)),
+            };
+            // the stream picks up again at the next command
+            assert!(matches!(parse(&input[skipped..], false), Parsed::Done(ref command, _) if command.command == Method::GET));
+        }
+    }
+
+    #[test]
+    fn stats_groups_of_the_server_are_an_error_here() {
+        let cache = Cache::new();
+        assert_eq!(cache.reply(b"stats settings\r\n"), b"SERVER_ERROR stats group not available here\r\n");
+        assert_eq!(cache.reply(b"stats conns\r\n"), b"SERVER_ERROR stats group not available here\r\n");
+    }
+
+    #[test]
+    fn keys_may_be_up_to_250_bytes() {
+        let cache = Cache::new();
+        let longest = "k".repeat(250);
+        assert_eq!(cache.reply(format!("set {} 0 0 1\r\nx\r\n", longest).as_bytes()), b"STORED\r\n");
+        assert_eq!(cache.reply(format!("get {}\r\n", longest).as_bytes()), format!("VALUE {} 0 1\r\nx\r\nEND\r\n", longest).into_bytes());
+        let input = format!("set {}k 0 0 1\r\nx\r\n", longest);
+        match parse(input.as_bytes(), false) {
+            Parsed::Error(error, _) => assert_eq!(encode_text(&error.response(true)), b"CLIENT_ERROR bad command line format\r\n"),
+            _ => panic!("a 251 byte key was accepted"),
+        }
+    }
+
+    #[test]
+    fn empty_value_is_not_a_miss() {
+        let cache = Cache::new();
+        assert_eq!(cache.reply(b"set empty 0 0 0\r\n\r\n"), b"STORED\r\n");
+        assert_eq!(cache.reply(b"get empty\r\n"), b"VALUE empty 0 0\r\n\r\nEND\r\n");
+        assert_eq!(cache.reply(b"get missing\r\n"), b"END\r\n");
+    }
+
+    #[test]
+    fn value_length_counts_bytes_not_chars() {
+        let cache = Cache::new();
+        assert_eq!(cache.reply("set word 0 0 6\r\nhéllo\r\n".as_bytes()), b"STORED\r\n");
+        assert_eq!(cache.reply(b"get word\r\n"), "VALUE word 0 6\r\nhéllo\r\nEND\r\n".as_bytes());
+    }
+
+    #[test]
+    fn append_and_prepend_keep_the_flags_and_running_ttl() {
+        let cache = Cache::new();
+        assert_eq!(cache.run(b"set key 42 100 5\r\nvalue\r\n"), Response::Stored);
+        thread::sleep(Duration::from_millis(1100));
+        // their own flags and exptime are ignored
+        assert_eq!(cache.run(b"append key 7 1000 1\r\n>\r\n"), Response::Stored);
+        assert_eq!(cache.run(b"prepend key 7 0 1\r\n<\r\n"), Response::Stored);
+        let shard = cache.storage.shard(b"key");
+        let item = shard.peek(b"key").unwrap();
+        assert_eq!(&*item.value(), b"<value>");
+        assert_eq!(item.flags(), 42);
+        // a second into the 100, neither reset nor extended
+        assert_eq!(item.ttl(), 98);
+    }
+
+    #[test]
+    fn metadump_is_an_error_here() {
+        let cache = Cache::new();
+        assert_eq!(cache.reply(b"lru_crawler metadump all\r\n"), b"SERVER_ERROR metadump not available here\r\n");
     }
 }
@@ -0,0 +1,260 @@
+use std::sync::atomic::AtomicU64;
+
+use proto::{self, Item};
+use storage::{Shard, Store};
+use stats::Stats;
+
+// First byte of every binary request, which is how a connection is told
+// apart from the text protocol
+pub const MAGIC_REQUEST: u8 = 0x80;
+const MAGIC_RESPONSE: u8 = 0x81;
+const HEADER_LEN: usize = 24;
+
+const OP_GET: u8 = 0x00;
+const OP_SET: u8 = 0x01;
+const OP_DELETE: u8 = 0x04;
+
+const STATUS_OK: u16 = 0x0000;
+const STATUS_KEY_NOT_FOUND: u16 = 0x0001;
+const STATUS_KEY_EXISTS: u16 = 0x0002;
+const STATUS_VALUE_TOO_LARGE: u16 = 0x0003;
+const STATUS_INVALID_ARGUMENTS: u16 = 0x0004;
+const STATUS_UNKNOWN_COMMAND: u16 = 0x0081;
+const STATUS_NOT_SUPPORTED: u16 = 0x0083;
+const STATUS_TEMPORARY_FAILURE: u16 = 0x0086;
+const STATUS_OUT_OF_MEMORY: u16 = 0x0082;
+
+#[derive(Debug)]
+pub struct Request {
+    opcode: u8,
+    opaque: u32,
+    cas: u64,
+    extras: Vec<u8>,
+    key: Vec<u8>,
+    // known from the header, before the value itself is read
+    value_len: usize,
+    value: Vec<u8>,
+}
+
+pub enum Parsed {
+    // the request and the number of bytes it took up
+    Done(Request, usize),
+    // the buffer has to grow to at least this many bytes before parsing again
+    Incomplete(usize),
+    // a set whose value is still to come, without it, and the length of the
+    // whole request
+    Header(Request, usize),
+    // not a binary request, there is no way to get back in sync; the error
+    // reply is there when the header is intact enough to address one
+    Invalid(Option<Vec<u8>>),
+}
+
+fn read_u16(buf: &[u8]) -> u16 {
+    u16::from_be_bytes([buf[0], buf[1]])
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+// magic, opcode, key length, extras length, data type, vbucket, total body
+// length, opaque and cas, followed by extras, key and value
+pub fn parse(buf: &[u8]) -> Parsed {
+    if buf.len() < HEADER_LEN {
+        return Parsed::Incomplete(HEADER_LEN);
+    }
+    if buf[0] != MAGIC_REQUEST {
+        return Parsed::Invalid(None);
+    }
+
+    let key_len = read_u16(&buf[2..]) as usize;
+    let extras_len = buf[4] as usize;
+    let body_len = read_u32(&buf[8..]) as usize;
+    if key_len + extras_len > body_len {
+        return Parsed::Invalid(header_error(buf, STATUS_INVALID_ARGUMENTS, "Invalid arguments"));
+    }
+    let total = HEADER_LEN + body_len;
+    let key_end = HEADER_LEN + extras_len + key_len;
+    let request = |value_end: usize| Request {
+        opcode: buf[1],
+        opaque: read_u32(&buf[12..]),
+        cas: read_u64(&buf[16..]),
+        extras: buf[HEADER_LEN..HEADER_LEN + extras_len].to_vec(),
+        key: buf[HEADER_LEN + extras_len..key_end].to_vec(),
+        value_len: total - key_end,
+        value: buf[key_end..value_end].to_vec(),
+    };
+    if buf.len() < total {
+        // with the key in, a set can be refused before its value is read
+        return match buf[1] {
+            OP_SET if buf.len() >= key_end => Parsed::Header(request(key_end), total),
+            OP_SET => Parsed::Incomplete(key_end),
+            _ => Parsed::Incomplete(total),
+        };
+    }
+    Parsed::Done(request(total), total)
+}
+
+struct Response<'a> {
+    status: u16,
+    cas: u64,
+    extras: &'a [u8],
+    value: &'a [u8],
+}
+
+impl<'a> Response<'a> {
+    fn status(status: u16) -> Response<'a> {
+        Response { status, cas: 0, extras: &[], value: &[] }
+    }
+
+    fn error(status: u16, message: &'a str) -> Response<'a> {
+        Response { value: message.as_bytes(), ..Response::status(status) }
+    }
+
+    fn encode(&self, request: &Request) -> Vec<u8> {
+        let body_len = self.extras.len() + self.value.len();
+        let mut buf = Vec::with_capacity(HEADER_LEN + body_len);
+        buf.push(MAGIC_RESPONSE);
+        buf.push(request.opcode);
+        buf.extend(&0u16.to_be_bytes());
+        buf.push(self.extras.len() as u8);
+        buf.push(0);
+        buf.extend(&self.status.to_be_bytes());
+        buf.extend(&(body_len as u32).to_be_bytes());
+        buf.extend(&request.opaque.to_be_bytes());
+        buf.extend(&self.cas.to_be_bytes());
+        buf.extend(self.extras);
+        buf.extend(self.value);
+        buf
+    }
+}
+
+// Answers a request from its header alone, for when the rest of it is
+// never going to be read
+fn header_error(buf: &[u8], status: u16, message: &str) -> Option<Vec<u8>> {
+    if buf.len() < HEADER_LEN || buf[0] != MAGIC_REQUEST {
+        return None;
+    }
+    let request = Request {
+        opcode: buf[1],
+        opaque: read_u32(&buf[12..]),
+        cas: 0,
+        extras: Vec::new(),
+        key: Vec::new(),
+        value_len: 0,
+        value: Vec::new(),
+    };
+    Some(Response::error(status, message).encode(&request))
+}
+
+// For a request whose body would not fit in the read buffer
+pub fn too_large(buf: &[u8]) -> Option<Vec<u8>> {
+    header_error(buf, STATUS_VALUE_TOO_LARGE, "Too large")
+}
+
+// The size and memory checks of a set, which only need its header and key
+fn refuse_set<S: Store>(request: &Request, storage: &S) -> Option<Vec<u8>> {
+    if request.value_len > storage.max_item_size() {
+        return Some(Response::error(STATUS_VALUE_TOO_LARGE, "Too large").encode(request));
+    }
+    if !storage.shard(&request.key).can_hold(&request.key, request.value_len) {
+        return Some(Response::error(STATUS_OUT_OF_MEMORY, "Out of memory").encode(request));
+    }
+    None
+}
+
+// The reply to a set refused before its value is read, like
+// proto::refuse_header, or None if it has to be read after all
+pub fn refuse_header<S: Store>(request: &Request, storage: &S, stats: &Stats, read_only: bool) -> Option<Vec<u8>> {
+    if read_only {
+        return Some(Response::error(STATUS_NOT_SUPPORTED, "read-only").encode(request));
+    }
+    // a bad key or extras get their own answer once the request is complete
+    if !proto::valid_key(&request.key) || request.extras.len() != 8 {
+        return None;
+    }
+    let refused = refuse_set(request, storage)?;
+    stats.cmd_set.incr();
+    Some(refused)
+}
+
+// For requests the server refuses to run right now, such as past the rate limit
+pub fn temporary_failure(request: &Request, message: &str) -> Vec<u8> {
+    Response::error(STATUS_TEMPORARY_FAILURE, message).encode(request)
+}
+
+pub fn handle<S: Store>(request: Request, storage: &S, cas_counter: &AtomicU64, stats: &Stats, read_only: bool) -> Vec<u8> {
+    if !matches!(request.opcode, OP_GET | OP_SET | OP_DELETE) {
+        return Response::error(STATUS_UNKNOWN_COMMAND, "Unknown command").encode(&request);
+    }
+    if read_only && request.opcode != OP_GET {
+        return Response::error(STATUS_NOT_SUPPORTED, "read-only").encode(&request);
+    }
+    // binary keys are length prefixed, so they could carry anything, even nulls
+    if !proto::valid_key(&request.key) {
+        return Response::error(STATUS_INVALID_ARGUMENTS, "Invalid arguments").encode(&request);
+    }
+    let key = request.key.clone();
+
+    match request.opcode {
+        OP_GET => {
+            stats.cmd_get.incr();
+            let mut shard = storage.shard(&key);
+            match shard.fetch(&key) {
+                Some(item) => {
+                    stats.get_hits.incr();
+                    let flags = item.flags().to_be_bytes();
+                    let value = item.value();
+                    Response { status: STATUS_OK, cas: item.cas(), extras: &flags, value: &value }.encode(&request)
+                },
+                None => {
+                    stats.get_misses.incr();
+                    Response::error(STATUS_KEY_NOT_FOUND, "Not found").encode(&request)
+                },
+            }
+        },
+        OP_SET => {
+            stats.cmd_set.incr();
+            // <flags> <expiration>
+            if request.extras.len() != 8 {
+                return Response::error(STATUS_INVALID_ARGUMENTS, "Invalid arguments").encode(&request);
+            }
+            if let Some(refused) = refuse_set(&request, storage) {
+                return refused;
+            }
+            let flags = read_u32(&request.extras);
+            let exptime = read_u32(&request.extras[4..]) as i64;
+
+            let mut shard = storage.shard(&key);
+            // a non-zero cas turns the set into a compare and swap
+            if request.cas != 0 {
+                match shard.get(&key) {
+                    Some(item) if item.cas() != request.cas => return Response::error(STATUS_KEY_EXISTS, "Data exists for key.").encode(&request),
+                    Some(_) => {},
+                    None => return Response::error(STATUS_KEY_NOT_FOUND, "Not found").encode(&request),
+                }
+            }
+            let cas = proto::next_cas(cas_counter);
+            shard.set(key, Item::new(request.value.clone(), flags, exptime, cas));
+            Response { cas, ..Response::status(STATUS_OK) }.encode(&request)
+        },
+        OP_DELETE => {
+            let mut shard = storage.shard(&key);
+            match shard.get(&key) {
+                Some(item) if request.cas != 0 && item.cas() != request.cas => Response::error(STATUS_KEY_EXISTS, "Data exists for key.").encode(&request),
+                Some(_) => {
+                    shard.remove(&key);
+                    Response::status(STATUS_OK).encode(&request)
+                },
+                None => Response::error(STATUS_KEY_NOT_FOUND, "Not found").encode(&request),
+            }
+        },
+        _ => unreachable!("unknown opcodes are answered above"),
+    }
+}
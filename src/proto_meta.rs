@@ -0,0 +1,375 @@
+use std::str;
+use std::sync::atomic::AtomicU64;
+
+use proto::{self, Item, ParseError};
+use storage::{Shard, Store};
+use stats::Stats;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Method {
+    Get,
+    Set,
+    Delete,
+    Noop,
+}
+
+// How ms stores its value, picked with the M flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+}
+
+// Meta commands take single letter flags, some of them followed by a token
+#[derive(Debug, Default)]
+struct Flags {
+    // v, t, f, c, k and s ask for parts of the item in the reply
+    value: bool,
+    ttl: bool,
+    client_flags: bool,
+    cas: bool,
+    key: bool,
+    size: bool,
+    // q leaves out the reply when it is the expected outcome
+    quiet: bool,
+    // O is echoed back so pipelined replies can be matched up
+    opaque: Option<Vec<u8>>,
+    // T sets a new ttl, F the client flags, C the cas to compare against
+    new_ttl: Option<i64>,
+    new_client_flags: Option<u32>,
+    compare_cas: Option<u64>,
+    mode: Option<Mode>,
+}
+
+#[derive(Debug)]
+pub struct Request {
+    method: Method,
+    key: Vec<u8>,
+    flags: Flags,
+    // the length of the data block of ms, which is the body once read
+    datalen: usize,
+    body: Vec<u8>,
+}
+
+pub enum Parsed {
+    // the request and the number of bytes it took up
+    Done(Request, usize),
+    // the buffer has to grow to at least this many bytes before parsing again
+    Incomplete(usize),
+    // an ms line whose data block is still to come, and the length of both
+    Header(Request, usize),
+    // the offending line has to be skipped to get back in sync
+    Error(ParseError, usize),
+}
+
+// Meta commands share the connection with the classic text commands, they
+// are told apart by their two letter name
+pub fn is_meta(buf: &[u8]) -> bool {
+    buf.len() >= 3 && buf[0] == b'm' && matches!(buf[1], b'g' | b's' | b'd' | b'n') && matches!(buf[2], b' ' | b'\r' | b'\n')
+}
+
+fn parse_number<T: str::FromStr>(token: &[u8]) -> Result<T, ParseError> {
+    str::from_utf8(token).ok().and_then(|token| token.parse().ok()).ok_or(ParseError::BadFormat)
+}
+
+fn parse_flags(tokens: &[&[u8]]) -> Result<Flags, ParseError> {
+    let mut flags = Flags::default();
+    for token in tokens {
+        let (flag, rest) = token.split_first().ok_or(ParseError::BadFormat)?;
+        match *flag {
+            b'v' => flags.value = true,
+            b't' => flags.ttl = true,
+            b'f' => flags.client_flags = true,
+            b'c' => flags.cas = true,
+            b'k' => flags.key = true,
+            b's' => flags.size = true,
+            b'q' => flags.quiet = true,
+            b'O' => flags.opaque = Some(rest.to_vec()),
+            b'T' => flags.new_ttl = Some(parse_number(rest)?),
+            b'F' => flags.new_client_flags = Some(parse_number(rest)?),
+            b'C' => flags.compare_cas = Some(parse_number(rest)?),
+            b'M' => flags.mode = Some(match rest {
+                b"S" | b"s" => Mode::Set,
+                b"E" | b"e" => Mode::Add,
+                b"R" | b"r" => Mode::Replace,
+                b"A" | b"a" => Mode::Append,
+                b"P" | b"p" => Mode::Prepend,
+                _ => return Err(ParseError::BadFormat),
+            }),
+            _ => return Err(ParseError::BadFormat),
+        }
+    }
+    Ok(flags)
+}
+
+// <command> [<key> [<datalen>]] <flags>*\r\n, with a data block after ms.
+// Lenient also takes a bare \n at the end of the line.
+pub fn parse(buf: &[u8], lenient: bool) -> Parsed {
+    if proto::line_too_long(buf) {
+        return Parsed::Error(ParseError::LineTooLong, buf.len());
+    }
+    let eol = match buf.iter().position(|&b| b == b'\n') {
+        Some(eol) => eol,
+        None => return Parsed::Incomplete(buf.len() + 1),
+    };
+    let line_len = eol + 1;
+    let line = match buf[..eol].last() {
+        Some(&b'\r') => &buf[..eol - 1],
+        _ if lenient => &buf[..eol],
+        _ => return Parsed::Error(ParseError::BadFormat, line_len),
+    };
+    let tokens: Vec<&[u8]> = line.split(|&b| b == b' ').filter(|token| !token.is_empty()).collect();
+
+    let method = match tokens.first().cloned().unwrap_or_default() {
+        b"mg" => Method::Get,
+        b"ms" => Method::Set,
+        b"md" => Method::Delete,
+        b"mn" => Method::Noop,
+        verb => return Parsed::Error(ParseError::UnknownCommand(verb.to_vec()), line_len),
+    };
+    if method == Method::Noop {
+        return Parsed::Done(Request { method, key: Vec::new(), flags: Flags::default(), datalen: 0, body: Vec::new() }, line_len);
+    }
+
+    let key = match tokens.get(1) {
+        Some(key) if proto::valid_key(key) => key.to_vec(),
+        _ => return Parsed::Error(ParseError::BadFormat, line_len),
+    };
+    let (datalen, flag_tokens) = match method {
+        Method::Set => match tokens.get(2).map(|token| parse_number::<usize>(token)) {
+            Some(Ok(datalen)) => (datalen, &tokens[3..]),
+            _ => return Parsed::Error(ParseError::BadFormat, line_len),
+        },
+        _ => (0, &tokens[2..]),
+    };
+    let flags = match parse_flags(flag_tokens) {
+        Ok(flags) => flags,
+        Err(error) => return Parsed::Error(error, line_len),
+    };
+    let request = Request { method, key, flags, datalen, body: Vec::new() };
+    if method != Method::Set {
+        return Parsed::Done(request, line_len);
+    }
+
+    let end = line_len.saturating_add(datalen);
+    if buf.len() < end.saturating_add(2) {
+        return Parsed::Header(request, end.saturating_add(2));
+    }
+    if &buf[end..end + 2] != b"\r\n" {
+        return match buf[end..].iter().position(|&b| b == b'\n') {
+            Some(newline) => Parsed::Error(ParseError::BadDataChunk, end + newline + 1),
+            None => Parsed::Incomplete(buf.len() + 1),
+        };
+    }
+    Parsed::Done(Request { body: buf[line_len..end].to_vec(), ..request }, end + 2)
+}
+
+// <code> <flags>*\r\n with the flags the client asked to get back
+fn status(code: &str, request: &Request, item: Option<&Item>) -> Vec<u8> {
+    let flags = &request.flags;
+    let mut buf = code.as_bytes().to_vec();
+    if let Some(item) = item {
+        if code == "VA" {
+            buf.extend(format!(" {}", item.value_len()).into_bytes());
+        }
+        if flags.ttl {
+            buf.extend(format!(" t{}", item.ttl()).into_bytes());
+        }
+        if flags.client_flags {
+            buf.extend(format!(" f{}", item.flags()).into_bytes());
+        }
+        if flags.cas {
+            buf.extend(format!(" c{}", item.cas()).into_bytes());
+        }
+        if flags.size {
+            buf.extend(format!(" s{}", item.value_len()).into_bytes());
+        }
+    }
+    if flags.key {
+        buf.extend(b" k");
+        buf.extend(&request.key);
+    }
+    if let Some(ref opaque) = flags.opaque {
+        buf.extend(b" O");
+        buf.extend(opaque);
+    }
+    buf.extend(b"\r\n");
+    if let (Some(item), "VA") = (item, code) {
+        buf.extend(item.value().iter());
+        buf.extend(b"\r\n");
+    }
+    buf
+}
+
+fn error(message: &str) -> Vec<u8> {
+    format!("SERVER_ERROR {}\r\n", message).into_bytes()
+}
+
+// The size and memory checks of ms, which only need its line
+fn refuse_set<S: Store>(request: &Request, storage: &S) -> Option<Vec<u8>> {
+    if request.datalen > storage.max_item_size() {
+        return Some(error("object too large for cache"));
+    }
+    let shard = storage.shard(&request.key);
+    // append and prepend add to the value already there
+    let existing = match request.flags.mode {
+        Some(Mode::Append) | Some(Mode::Prepend) => shard.peek(&request.key).map_or(0, |item| item.value_len()),
+        _ => 0,
+    };
+    if !shard.can_hold(&request.key, existing + request.datalen) {
+        return Some(error("out of memory storing object"));
+    }
+    None
+}
+
+// The reply to an ms refused before its data block is read, like
+// proto::refuse_header, or None if it has to be read after all
+pub fn refuse_header<S: Store>(request: &Request, storage: &S, stats: &Stats, read_only: bool) -> Option<Vec<u8>> {
+    if read_only {
+        return Some(error("read-only"));
+    }
+    let refused = refuse_set(request, storage)?;
+    stats.cmd_set.incr();
+    Some(refused)
+}
+
+pub fn handle<S: Store>(request: Request, storage: &S, cas_counter: &AtomicU64, stats: &Stats, read_only: bool) -> Vec<u8> {
+    // mg only changes the item when it is given a new ttl
+    let mutation = match request.method {
+        Method::Set | Method::Delete => true,
+        Method::Get => request.flags.new_ttl.is_some(),
+        Method::Noop => false,
+    };
+    if read_only && mutation {
+        return error("read-only");
+    }
+    match request.method {
+        Method::Noop => b"MN\r\n".to_vec(),
+        Method::Get => {
+            stats.cmd_get.incr();
+            let mut shard = storage.shard(&request.key);
+            // only handing out the value is a fetch
+            let item = if request.flags.value { shard.fetch(&request.key) } else { shard.get(&request.key) };
+            match item {
+                Some(item) => {
+                    stats.get_hits.incr();
+                    if let Some(ttl) = request.flags.new_ttl {
+                        item.touch(ttl);
+                    }
+                    let code = if request.flags.value { "VA" } else { "HD" };
+                    status(code, &request, Some(item))
+                },
+                None => {
+                    stats.get_misses.incr();
+                    // a miss is what q hides for mg
+                    if request.flags.quiet { Vec::new() } else { status("EN", &request, None) }
+                },
+            }
+        },
+        Method::Set => {
+            stats.cmd_set.incr();
+            if let Some(refused) = refuse_set(&request, storage) {
+                return refused;
+            }
+            let mut shard = storage.shard(&request.key);
+            let mode = request.flags.mode.unwrap_or(Mode::Set);
+            let existing = match shard.get(&request.key) {
+                Some(item) if request.flags.compare_cas.is_some_and(|cas| cas != item.cas()) => return status("EX", &request, None),
+                Some(item) => Some(item.value_len()),
+                None if request.flags.compare_cas.is_some() => return status("NF", &request, None),
+                None => None,
+            };
+            let stored = match mode {
+                Mode::Set => true,
+                Mode::Add => existing.is_none(),
+                Mode::Replace | Mode::Append | Mode::Prepend => existing.is_some(),
+            };
+            if !stored {
+                return status("NS", &request, None);
+            }
+
+            let cas = proto::next_cas(cas_counter);
+            let reply = match mode {
+                // the item being extended keeps its flags and expiry
+                Mode::Append | Mode::Prepend => {
+                    let item = shard.get(&request.key).expect("checked above");
+                    if mode == Mode::Append {
+                        item.append(&request.body, cas);
+                    } else {
+                        item.prepend(&request.body, cas);
+                    }
+                    let reply = status("HD", &request, Some(item));
+                    shard.resize(&request.key);
+                    reply
+                },
+                _ => {
+                    let item = Item::new(request.body.clone(), request.flags.new_client_flags.unwrap_or(0), request.flags.new_ttl.unwrap_or(0), cas);
+                    let reply = status("HD", &request, Some(&item));
+                    shard.set(request.key.clone(), item);
+                    reply
+                },
+            };
+            if request.flags.quiet { Vec::new() } else { reply }
+        },
+        Method::Delete => {
+            let mut shard = storage.shard(&request.key);
+            match shard.get(&request.key) {
+                Some(item) if request.flags.compare_cas.is_some_and(|cas| cas != item.cas()) => status("EX", &request, None),
+                Some(_) => {
+                    shard.remove(&request.key);
+                    if request.flags.quiet { Vec::new() } else { status("HD", &request, None) }
+                },
+                None => status("NF", &request, None),
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::Storage;
+
+    fn run(input: &[u8]) -> Vec<u8> {
+        let request = match parse(input, false) {
+            Parsed::Done(request, consumed) if consumed == input.len() => request,
+            _ => panic!("not a single complete command: {:?}", String::from_utf8_lossy(input)),
+        };
+        let storage = Storage::new(1024, 1024 * 1024, 64 << 20, 0);
+        handle(request, &storage, &AtomicU64::new(0), &Stats::default(), false)
+    }
+
+    #[test]
+    fn unknown_meta_command_is_an_error() {
+        match parse(b"mx key\r\nmn\r\n", false) {
+            Parsed::Error(ParseError::UnknownCommand(verb), 8) => assert_eq!(verb, b"mx"),
+            _ => panic!("mx was accepted"),
+        }
+        assert_eq!(run(b"mn\r\n"), b"MN\r\n");
+    }
+
+    #[test]
+    fn miss_echoes_the_key_and_opaque() {
+        assert_eq!(run(b"mg missing v k Oabc\r\n"), b"EN kmissing Oabc\r\n");
+        assert_eq!(run(b"mg missing v\r\n"), b"EN\r\n");
+    }
+
+    #[test]
+    fn set_can_be_refused_before_its_data_block() {
+        let request = match parse(b"ms key 100 T0\r\nabc", false) {
+            // the line, the data block and its \r\n
+            Parsed::Header(request, 117) => request,
+            _ => panic!("no header for a partial ms"),
+        };
+        let storage = Storage::new(1024, 64, 64 << 20, 0);
+        let stats = Stats::default();
+        assert_eq!(refuse_header(&request, &storage, &stats, true), Some(b"SERVER_ERROR read-only\r\n".to_vec()));
+        assert_eq!(refuse_header(&request, &storage, &stats, false), Some(b"SERVER_ERROR object too large for cache\r\n".to_vec()));
+        assert_eq!(stats.cmd_set.get(), 1);
+        let storage = Storage::new(1024, 1024, 64 << 20, 0);
+        assert_eq!(refuse_header(&request, &storage, &stats, false), None);
+    }
+}
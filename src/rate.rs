@@ -0,0 +1,70 @@
+use std::time::Instant;
+
+// Refills at `rate` tokens a second up to a second's worth, so a client may
+// burst that many commands after being quiet
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    // Whether there was a token left for one more command
+    pub fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::TokenBucket;
+
+    fn taken(bucket: &mut TokenBucket, now: Instant, tries: usize) -> usize {
+        (0..tries).filter(|_| bucket.take_at(now)).count()
+    }
+
+    #[test]
+    fn starts_with_a_seconds_worth() {
+        let mut bucket = TokenBucket::new(10);
+        let now = bucket.refilled;
+        assert_eq!(taken(&mut bucket, now, 20), 10);
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let mut bucket = TokenBucket::new(10);
+        let start = bucket.refilled;
+        assert_eq!(taken(&mut bucket, start, 10), 10);
+        assert_eq!(taken(&mut bucket, start + Duration::from_millis(500), 10), 5);
+        assert_eq!(taken(&mut bucket, start + Duration::from_millis(600), 10), 1);
+    }
+
+    #[test]
+    fn bursts_are_capped_at_a_seconds_worth() {
+        let mut bucket = TokenBucket::new(10);
+        let start = bucket.refilled;
+        assert_eq!(taken(&mut bucket, start + Duration::from_secs(60), 100), 10);
+    }
+}
@@ -0,0 +1,35 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use libc;
+use mio::unix::{self, PipeReader};
+
+// Write end of the self-pipe, the handler can only touch async-signal-safe state
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(_signum: libc::c_int) {
+    let fd = PIPE.load(Ordering::Relaxed);
+    unsafe {
+        libc::write(fd, b"!".as_ptr() as *const libc::c_void, 1);
+    }
+}
+
+// Turns SIGINT and SIGTERM into readiness of the returned pipe so the event
+// loop can shut down in an orderly fashion.
+pub fn install() -> io::Result<PipeReader> {
+    let (reader, writer) = unix::pipe()?;
+    PIPE.store(writer.as_raw_fd(), Ordering::Relaxed);
+    // the write end has to stay open for as long as the handler is installed
+    mem::forget(writer);
+
+    for &signum in &[libc::SIGINT, libc::SIGTERM] {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(signum, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(reader)
+}
@@ -0,0 +1,94 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use proto::Item;
+use storage::{Shard, Store};
+
+const MAGIC: &[u8; 8] = b"RMCSNAP1";
+
+// After the magic every item is written as
+//   <key length u32> <key> <flags u32> <exptime i64> <cas u64> <value length u32> <value>
+// all big endian, with exptime an absolute unix timestamp or 0 for never.
+// Items go out least recently used first, so loading them back in order
+// restores the recency within each shard.
+pub fn save<S: Store>(storage: &S, path: &Path) -> io::Result<usize> {
+    // written aside and renamed over, a crash never leaves half a snapshot
+    let tmp = path.with_extension("tmp");
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    out.write_all(MAGIC)?;
+    let mut saved = 0;
+    storage.try_for_each(|key, item| -> io::Result<()> {
+        out.write_all(&(key.len() as u32).to_be_bytes())?;
+        out.write_all(key)?;
+        out.write_all(&item.flags().to_be_bytes())?;
+        out.write_all(&item.exptime().to_be_bytes())?;
+        out.write_all(&item.cas().to_be_bytes())?;
+        let value = item.value();
+        out.write_all(&(value.len() as u32).to_be_bytes())?;
+        out.write_all(&value)?;
+        saved += 1;
+        Ok(())
+    })?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(saved)
+}
+
+fn read_u32(input: &mut dyn Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(input: &mut dyn Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_bytes(input: &mut dyn Read, len: u32) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    input.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+// Items that expired while the server was down are dropped. Returns how many
+// items were loaded and the highest cas among them.
+pub fn load<S: Store>(storage: &S, path: &Path) -> io::Result<(usize, u64)> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a remcached snapshot"));
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let mut loaded = 0;
+    let mut max_cas = 0;
+    loop {
+        let key_len = match read_u32(&mut input) {
+            Ok(key_len) => key_len,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        let key = read_bytes(&mut input, key_len)?;
+        let flags = read_u32(&mut input)?;
+        let exptime = read_u64(&mut input)? as i64;
+        let cas = read_u64(&mut input)?;
+        let value_len = read_u32(&mut input)?;
+        let value = read_bytes(&mut input, value_len)?;
+
+        max_cas = max_cas.max(cas);
+        if exptime != 0 && exptime <= now {
+            continue;
+        }
+        storage.shard(&key).set(key, Item::new(value, flags, exptime, cas));
+        loaded += 1;
+    }
+    Ok((loaded, max_cas))
+}
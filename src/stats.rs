@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// A counter bumped from every event loop
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn incr(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn decr(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    // totals since startup or the last `stats reset`
+    pub cmd_get: Counter,
+    pub cmd_set: Counter,
+    pub get_hits: Counter,
+    pub get_misses: Counter,
+    pub total_connections: Counter,
+    // clients closed right after accepting, past the connection limit or
+    // while overloaded
+    pub rejected_connections: Counter,
+    // bytes moved over client sockets
+    pub bytes_read: Counter,
+    pub bytes_written: Counter,
+    // gauges of the current state, never reset
+    pub curr_connections: Counter,
+}
+
+impl Stats {
+    pub fn reset(&self) {
+        for counter in &[&self.cmd_get, &self.cmd_set, &self.get_hits, &self.get_misses,
+                         &self.total_connections, &self.rejected_connections, &self.bytes_read, &self.bytes_written] {
+            counter.reset();
+        }
+    }
+}
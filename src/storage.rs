@@ -1,22 +1,591 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use proto::Item;
 
-trait StorageEngine {
-    fn new() -> Self;
-    fn set(&self, key: &str, value: str);
-    fn get(&self, key: &str) -> Option<&str>;
+const SHARDS: usize = 16;
+
+#[derive(Debug)]
+struct Entry {
+    item: Item,
+    // position in the recency order, higher is more recently used
+    tick: u64,
+    // bytes charged against the memory limit
+    size: usize,
+    // when it was stored or last fetched
+    accessed: Instant,
+    // times its value was handed to a client since it was stored
+    fetches: u64,
 }
 
-pub struct Storage {
+fn size_of(key: &[u8], item: &Item) -> usize {
+    key.len() + item.stored_len()
+}
+
+// Values at least threshold bytes are stored compressed, 0 turns it off
+fn compress(item: &mut Item, threshold: usize) {
+    if threshold > 0 && item.value_len() >= threshold {
+        item.compress();
+    }
+}
+
+// Where items are kept, the protocols only go through this. Keys are split
+// between shards, each behind its own lock, and holding a shard's guard keeps
+// read-modify-write commands atomic per key.
+pub trait Store: Send + Sync + 'static {
+    type Shard: Shard;
+
+    fn shard(&self, key: &[u8]) -> MutexGuard<'_, Self::Shard>;
+
+    fn shard_count(&self) -> usize;
+
+    // Visits the live items of one shard, least recently used first, with
+    // how long ago each was last fetched and how many times. Lets a large
+    // cache be walked one shard at a time.
+    fn for_each_in_shard<F>(&self, index: usize, f: F) where F: FnMut(&[u8], &Item, Duration, u64);
+
+    fn max_item_size(&self) -> usize;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // key and value bytes currently stored
+    fn bytes(&self) -> usize;
+
+    fn memory_limit(&self) -> usize;
+
+    fn set_memory_limit(&self, memory_limit: usize);
+
+    fn clear(&self);
+
+    // time since the least recently used item was last touched
+    fn oldest_age(&self) -> Option<Duration> {
+        None
+    }
+
+    fn evictions(&self) -> u64 {
+        0
+    }
+
+    fn reset_evictions(&self) {}
+
+    // Visits the live items shard by shard, stopping at the first error
+    fn try_for_each<E, F>(&self, mut f: F) -> Result<(), E> where F: FnMut(&[u8], &Item) -> Result<(), E> {
+        for index in 0..self.shard_count() {
+            let mut result = Ok(());
+            self.for_each_in_shard(index, |key, item, _, _| {
+                if result.is_ok() {
+                    result = f(key, item);
+                }
+            });
+            result?;
+        }
+        Ok(())
+    }
+}
+
+// One locked slice of a store's key space
+pub trait Shard: Send + 'static {
+    // Expired items are never handed out
+    fn get(&mut self, key: &[u8]) -> Option<&mut Item>;
+
+    // Like get, for commands that hand the value to the client, which
+    // counts as a fetch of the item
+    fn fetch(&mut self, key: &[u8]) -> Option<&mut Item> {
+        self.get(key)
+    }
+
+    // Like get, but leaves the item as it is, for commands that only have to
+    // know what is there
+    fn peek(&self, key: &[u8]) -> Option<&Item>;
+
+    fn set(&mut self, key: Vec<u8>, item: Item);
+
+    fn remove(&mut self, key: &[u8]) -> Option<Item>;
+
+    // Whether an item this large could be stored at all, even with
+    // everything else evicted
+    fn can_hold(&self, key: &[u8], value_len: usize) -> bool;
 
+    // Called after an item's value was changed in place, such as by append
+    // or incr
+    fn resize(&mut self, key: &[u8]);
+}
+
+// Counts and limits of the store as a whole, shared by its shards so a
+// limit holds for all items rather than a slice of it per shard
+#[derive(Debug)]
+struct Usage {
+    items: AtomicUsize,
+    max_items: usize,
+    bytes: AtomicUsize,
+    max_bytes: AtomicUsize,
+    // recency ticks are handed out store wide so shards can be compared
+    clock: AtomicU64,
+    evictions: AtomicU64,
+    // the tick of each shard's least recently used item, u64::MAX if empty
+    oldest: Vec<AtomicU64>,
+}
+
+impl Usage {
+    fn over_limit(&self) -> bool {
+        self.items.load(Ordering::Relaxed) > self.max_items || self.bytes.load(Ordering::Relaxed) > self.max_bytes.load(Ordering::Relaxed)
+    }
+
+    // The shard holding the least recently used item of the whole store
+    fn oldest_shard(&self) -> Option<usize> {
+        self.oldest.iter()
+            .map(|tick| tick.load(Ordering::Relaxed))
+            .enumerate()
+            .filter(|&(_, tick)| tick != u64::MAX)
+            .min_by_key(|&(_, tick)| tick)
+            .map(|(index, _)| index)
+    }
+}
+
+// One independently locked slice of the key space, ordered by recency
+#[derive(Debug)]
+pub struct LruShard {
+    index: usize,
+    entries: HashMap<Vec<u8>, Entry>,
+    lru: BTreeMap<u64, Vec<u8>>,
+    // this shard's part of usage.bytes
+    bytes: usize,
+    usage: Arc<Usage>,
+    // all shards of the store, to evict from whichever has the least
+    // recently used item
+    shards: Weak<Vec<Mutex<LruShard>>>,
+    // values at least this large are stored compressed, 0 for never
+    compress_threshold: usize,
+}
+
+impl LruShard {
+    fn new(index: usize, usage: Arc<Usage>, shards: Weak<Vec<Mutex<LruShard>>>, compress_threshold: usize) -> LruShard {
+        LruShard {
+            index,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            bytes: 0,
+            usage,
+            shards,
+            compress_threshold,
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.usage.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // Tells the other shards how old this one's least recently used item is
+    fn publish_oldest(&self) {
+        let oldest = self.lru.keys().next().cloned().unwrap_or(u64::MAX);
+        self.usage.oldest[self.index].store(oldest, Ordering::Relaxed);
+    }
+
+    fn charge(&mut self, size: usize) {
+        self.bytes += size;
+        self.usage.bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn refund(&mut self, size: usize) {
+        self.bytes -= size;
+        self.usage.bytes.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn clear(&mut self) {
+        self.usage.items.fetch_sub(self.entries.len(), Ordering::Relaxed);
+        let bytes = self.bytes;
+        self.refund(bytes);
+        self.entries.clear();
+        self.lru.clear();
+        self.publish_oldest();
+    }
+
+    // Evicts the least recently used items of the whole store until it is
+    // back within its limits, the most recently used item always stays even
+    // if it alone is over. Another shard is only evicted from if its lock is
+    // free, otherwise this one gives up its own oldest, so no two shards
+    // ever wait on each other.
+    fn evict_over_limit(&mut self) {
+        while self.usage.over_limit() && self.usage.items.load(Ordering::Relaxed) > 1 {
+            let evicted = match self.usage.oldest_shard() {
+                Some(index) if index != self.index => self.evict_from(index),
+                _ => false,
+            };
+            // this shard's own most recent item is the one just stored or resized
+            let evicted = evicted || (self.entries.len() > 1 && self.evict());
+            if !evicted {
+                break;
+            }
+        }
+    }
+
+    fn evict_from(&self, index: usize) -> bool {
+        let shards = match self.shards.upgrade() {
+            Some(shards) => shards,
+            None => return false,
+        };
+        let evicted = match shards[index].try_lock() {
+            Ok(mut shard) => shard.evict(),
+            Err(_) => false,
+        };
+        evicted
+    }
+
+    fn evict(&mut self) -> bool {
+        let oldest = match self.lru.keys().next() {
+            Some(&tick) => tick,
+            None => return false,
+        };
+        if let Some(key) = self.lru.remove(&oldest) {
+            debug!("evicting {}", String::from_utf8_lossy(&key));
+            if let Some(entry) = self.entries.remove(&key) {
+                self.usage.items.fetch_sub(1, Ordering::Relaxed);
+                self.refund(entry.size);
+                // an expired item is gone either way, reclaiming it is no eviction
+                if !entry.item.is_expired() {
+                    self.usage.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        self.publish_oldest();
+        true
+    }
+}
+
+impl Shard for LruShard {
+    // Looking an item up marks it as recently used. Expired items are only
+    // dropped when somebody looks them up.
+    fn get(&mut self, key: &[u8]) -> Option<&mut Item> {
+        if self.entries.get(key).is_some_and(|entry| entry.item.is_expired()) {
+            self.remove(key);
+            return None;
+        }
+
+        let tick = self.tick();
+        let previous = mem::replace(&mut self.entries.get_mut(key)?.tick, tick);
+        // the key moves to its new place in the order rather than being copied
+        let key_vec = self.lru.remove(&previous).unwrap_or_else(|| key.to_vec());
+        self.lru.insert(tick, key_vec);
+        self.publish_oldest();
+        self.entries.get_mut(key).map(|entry| &mut entry.item)
+    }
+
+    fn fetch(&mut self, key: &[u8]) -> Option<&mut Item> {
+        self.get(key)?;
+        let entry = self.entries.get_mut(key)?;
+        entry.accessed = Instant::now();
+        entry.fetches += 1;
+        Some(&mut entry.item)
+    }
+
+    fn peek(&self, key: &[u8]) -> Option<&Item> {
+        self.entries.get(key).map(|entry| &entry.item).filter(|item| !item.is_expired())
+    }
+
+    fn set(&mut self, key: Vec<u8>, mut item: Item) {
+        compress(&mut item, self.compress_threshold);
+        self.remove(&key);
+
+        let tick = self.tick();
+        let size = size_of(&key, &item);
+        self.charge(size);
+        self.usage.items.fetch_add(1, Ordering::Relaxed);
+        self.lru.insert(tick, key.clone());
+        self.entries.insert(key, Entry { item, tick, size, accessed: Instant::now(), fetches: 0 });
+        self.publish_oldest();
+        self.evict_over_limit();
+    }
+
+    fn can_hold(&self, key: &[u8], value_len: usize) -> bool {
+        key.len() + value_len <= self.usage.max_bytes.load(Ordering::Relaxed)
+    }
+
+    // Recharges an item whose value was changed in place, such as by append
+    // or incr, which may push the store over its memory limit.
+    fn resize(&mut self, key: &[u8]) {
+        let (old, size) = match self.entries.get_mut(key) {
+            Some(entry) => {
+                compress(&mut entry.item, self.compress_threshold);
+                let old = entry.size;
+                entry.size = size_of(key, &entry.item);
+                (old, entry.size)
+            }
+            None => return,
+        };
+        self.refund(old);
+        self.charge(size);
+        self.evict_over_limit();
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Item> {
+        let entry = self.entries.remove(key)?;
+        self.lru.remove(&entry.tick);
+        self.usage.items.fetch_sub(1, Ordering::Relaxed);
+        self.refund(entry.size);
+        self.publish_oldest();
+        Some(entry.item)
+    }
+}
+
+// The store the server uses unless it is given another, kept in memory and
+// evicting least recently used items
+#[derive(Debug)]
+pub struct Storage {
+    shards: Arc<Vec<Mutex<LruShard>>>,
+    usage: Arc<Usage>,
+    max_item_size: usize,
 }
 
-// TODO use a plugable engine
 impl Storage {
-    pub fn new() -> Storage {
-        Storage{}
+    // max_items and memory_limit are for the store as a whole, however the
+    // keys fall between the shards
+    pub fn new(max_items: usize, max_item_size: usize, memory_limit: usize, compress_threshold: usize) -> Storage {
+        let usage = Arc::new(Usage {
+            items: AtomicUsize::new(0),
+            max_items,
+            bytes: AtomicUsize::new(0),
+            max_bytes: AtomicUsize::new(memory_limit),
+            clock: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            oldest: (0..SHARDS).map(|_| AtomicU64::new(u64::MAX)).collect(),
+        });
+        let shards = Arc::new_cyclic(|shards| {
+            (0..SHARDS).map(|index| Mutex::new(LruShard::new(index, usage.clone(), shards.clone(), compress_threshold))).collect()
+        });
+        Storage {
+            shards,
+            usage,
+            max_item_size,
+        }
+    }
+
+    fn shard_index(&self, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+}
+
+impl Store for Storage {
+    type Shard = LruShard;
+
+    fn shard(&self, key: &[u8]) -> MutexGuard<'_, LruShard> {
+        self.shards[self.shard_index(key)].lock().unwrap()
+    }
+
+    fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn for_each_in_shard<F>(&self, index: usize, mut f: F) where F: FnMut(&[u8], &Item, Duration, u64) {
+        let shard = self.shards[index].lock().unwrap();
+        for key in shard.lru.values() {
+            match shard.entries.get(key) {
+                Some(entry) if !entry.item.is_expired() => f(key, &entry.item, entry.accessed.elapsed(), entry.fetches),
+                _ => {}
+            }
+        }
+    }
+
+    fn max_item_size(&self) -> usize {
+        self.max_item_size
+    }
+
+    fn len(&self) -> usize {
+        self.usage.items.load(Ordering::Relaxed)
+    }
+
+    fn bytes(&self) -> usize {
+        self.usage.bytes.load(Ordering::Relaxed)
+    }
+
+    fn memory_limit(&self) -> usize {
+        self.usage.max_bytes.load(Ordering::Relaxed)
+    }
+
+    // Lowering the limit evicts right away rather than on the next store
+    fn set_memory_limit(&self, memory_limit: usize) {
+        self.usage.max_bytes.store(memory_limit, Ordering::Relaxed);
+        while self.usage.over_limit() && self.usage.items.load(Ordering::Relaxed) > 1 {
+            let evicted = match self.usage.oldest_shard() {
+                Some(index) => self.shards[index].lock().unwrap().evict(),
+                None => false,
+            };
+            if !evicted {
+                break;
+            }
+        }
+    }
+
+    fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().clear();
+        }
+    }
+
+    fn oldest_age(&self) -> Option<Duration> {
+        self.shards.iter().filter_map(|shard| {
+            let shard = shard.lock().unwrap();
+            let key = shard.lru.values().next()?;
+            shard.entries.get(key).map(|entry| entry.accessed.elapsed())
+        }).max()
+    }
+
+    fn evictions(&self) -> u64 {
+        self.usage.evictions.load(Ordering::Relaxed)
+    }
+
+    fn reset_evictions(&self) {
+        self.usage.evictions.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(storage: &Storage, key: &str) {
+        storage.shard(key.as_bytes()).set(key.as_bytes().to_vec(), Item::new(b"value".to_vec(), 0, 0, 0));
+    }
+
+    fn shard_sizes(storage: &Storage) -> Vec<usize> {
+        (0..storage.shard_count()).map(|index| {
+            let mut items = 0;
+            storage.for_each_in_shard(index, |_, _, _, _| items += 1);
+            items
+        }).collect()
+    }
+
+    #[test]
+    fn keys_spread_over_all_shards() {
+        let storage = Storage::new(100_000, 1024, 64 << 20, 0);
+        for i in 0..1600 {
+            set(&storage, &format!("key:{}", i));
+        }
+        let sizes = shard_sizes(&storage);
+        assert_eq!(sizes.iter().sum::<usize>(), 1600);
+        // 100 each on average
+        assert!(sizes.iter().all(|&items| items > 50 && items < 150), "{:?}", sizes);
+    }
+
+    #[test]
+    fn limits_hold_for_the_whole_store() {
+        let storage = Storage::new(16, 1024, 64 << 20, 0);
+        for i in 0..16 {
+            set(&storage, &format!("key:{}", i));
+        }
+        assert_eq!(storage.len(), 16);
+        assert_eq!(storage.evictions(), 0);
+        set(&storage, "key:16");
+        assert_eq!(storage.len(), 16);
+        assert_eq!(storage.evictions(), 1);
+    }
+
+    fn keys(storage: &Storage) -> Vec<String> {
+        let mut keys = Vec::new();
+        storage.try_for_each(|key, _| {
+            keys.push(String::from_utf8_lossy(key).into_owned());
+            Ok::<(), ()>(())
+        }).unwrap();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn one_more_than_fits_evicts_the_least_recently_used() {
+        let storage = Storage::new(4, 1024, 64 << 20, 0);
+        for key in &["a", "b", "c", "d"] {
+            set(&storage, key);
+        }
+        // a is used again, which leaves b the least recently used
+        assert!(storage.shard(b"a").get(b"a").is_some());
+        set(&storage, "e");
+        assert_eq!(keys(&storage), ["a", "c", "d", "e"]);
+        set(&storage, "f");
+        assert_eq!(keys(&storage), ["a", "d", "e", "f"]);
+        assert_eq!(storage.evictions(), 2);
+    }
+
+    #[test]
+    fn peeking_leaves_the_recency_order_alone() {
+        let storage = Storage::new(2, 1024, 64 << 20, 0);
+        set(&storage, "a");
+        set(&storage, "b");
+        assert!(storage.shard(b"a").peek(b"a").is_some());
+        set(&storage, "c");
+        assert_eq!(keys(&storage), ["b", "c"]);
+    }
+
+    #[test]
+    fn bytes_follow_sets_appends_and_deletes() {
+        let storage = Storage::new(16, 1024, 64 << 20, 0);
+        set(&storage, "a");
+        assert_eq!(storage.bytes(), 6);
+        set(&storage, "bb");
+        assert_eq!(storage.bytes(), 13);
+        {
+            let mut shard = storage.shard(b"a");
+            shard.get(b"a").unwrap().append(b"-more", 1);
+            shard.resize(b"a");
+        }
+        assert_eq!(storage.bytes(), 18);
+        // a set over an existing key replaces its size rather than adding to it
+        set(&storage, "a");
+        assert_eq!(storage.bytes(), 13);
+        assert!(storage.shard(b"a").remove(b"a").is_some());
+        assert_eq!(storage.bytes(), 7);
+        assert!(storage.shard(b"bb").remove(b"bb").is_some());
+        assert_eq!(storage.bytes(), 0);
+    }
+
+    #[test]
+    fn deleted_and_expired_items_are_not_evictions() {
+        let storage = Storage::new(3, 1024, 64 << 20, 0);
+        set(&storage, "a");
+        storage.shard(b"expired").set(b"expired".to_vec(), Item::new(b"value".to_vec(), 0, -1, 0));
+        set(&storage, "b");
+        assert!(storage.shard(b"a").remove(b"a").is_some());
+        // the deleted item made room
+        set(&storage, "c");
+        assert_eq!(storage.evictions(), 0);
+        // the expired one is the oldest, it makes room without counting
+        set(&storage, "d");
+        assert_eq!(keys(&storage), ["b", "c", "d"]);
+        assert_eq!(storage.evictions(), 0);
+        set(&storage, "e");
+        assert_eq!(keys(&storage), ["c", "d", "e"]);
+        assert_eq!(storage.evictions(), 1);
+        // and neither comes back
+        assert!(storage.shard(b"a").get(b"a").is_none());
+        assert!(storage.shard(b"expired").get(b"expired").is_none());
+        assert_eq!(storage.len(), 3);
     }
 
-    pub fn set(&self, key: &str, value: &str)
-    {
-    
+    #[test]
+    fn only_fetches_are_counted() {
+        let storage = Storage::new(16, 1024, 64 << 20, 0);
+        set(&storage, "key");
+        let fetches = |storage: &Storage| {
+            let mut fetches = Vec::new();
+            storage.for_each_in_shard(storage.shard_index(b"key"), |_, _, _, count| fetches.push(count));
+            fetches
+        };
+        assert!(storage.shard(b"key").get(b"key").is_some());
+        assert!(storage.shard(b"key").peek(b"key").is_some());
+        assert_eq!(fetches(&storage), [0]);
+        assert!(storage.shard(b"key").fetch(b"key").is_some());
+        assert!(storage.shard(b"key").fetch(b"key").is_some());
+        assert_eq!(fetches(&storage), [2]);
+        // a new value starts over
+        set(&storage, "key");
+        assert_eq!(fetches(&storage), [0]);
     }
 }
@@ -0,0 +1,101 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::{ServerConfig, ServerConnection};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+
+use net::Stream;
+
+fn invalid_data<E: ::std::fmt::Debug>(what: &str, e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {:?}", what, e))
+}
+
+pub fn load_config(cert: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid_data("failed to load certificate", e))?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| invalid_data("failed to load private key", e))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid_data("bad certificate or key", e))?;
+    Ok(Arc::new(config))
+}
+
+// A non-blocking TLS session on top of an accepted socket. Reading may have
+// to send handshake records and writing may leave records queued, so
+// anything rustls still wants to send is flushed whenever the socket allows.
+#[derive(Debug)]
+pub struct TlsStream {
+    session: ServerConnection,
+    socket: Stream,
+}
+
+impl TlsStream {
+    pub fn new(socket: Stream, config: Arc<ServerConfig>) -> io::Result<TlsStream> {
+        let session = ServerConnection::new(config).map_err(|e| invalid_data("failed to start tls session", e))?;
+        Ok(TlsStream { session, socket })
+    }
+
+    pub fn socket(&self) -> &Stream {
+        &self.socket
+    }
+
+    pub fn wants_write(&self) -> bool {
+        self.session.wants_write()
+    }
+
+    // Stops quietly when the socket would block, whatever is left is sent on
+    // the next writable event.
+    pub fn flush_records(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            match self.session.write_tls(&mut self.socket) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                // the peer went away without a close_notify
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            if self.session.read_tls(&mut self.socket)? == 0 {
+                return Ok(0);
+            }
+            let processed = self.session.process_new_packets();
+            // handshake replies and alerts have to go out either way
+            self.flush_records()?;
+            processed.map_err(|e| invalid_data("tls error", e))?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.flush_records()?;
+        let n = self.session.writer().write(buf)?;
+        self.flush_records()?;
+        if n == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_records()
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use mio::Sender;
+
+use Message;
+
+// Event loops with clients that ran `watch`. Every loop publishes its events
+// to all of them, and each hands the lines to its own watchers.
+#[derive(Debug, Default)]
+pub struct Watch {
+    subscribed: AtomicUsize,
+    loops: Mutex<HashMap<usize, Sender<Message>>>,
+    // numbers the events, so a watcher can tell when lines were dropped
+    next_gid: AtomicU64,
+}
+
+impl Watch {
+    // Cheap enough to ask on every command
+    pub fn is_active(&self) -> bool {
+        self.subscribed.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn subscribe(&self, id: usize, sender: Sender<Message>) {
+        let mut loops = self.loops.lock().unwrap();
+        loops.insert(id, sender);
+        self.subscribed.store(loops.len(), Ordering::Relaxed);
+    }
+
+    pub fn unsubscribe(&self, id: usize) {
+        let mut loops = self.loops.lock().unwrap();
+        loops.remove(&id);
+        self.subscribed.store(loops.len(), Ordering::Relaxed);
+    }
+
+    // ts=<unix time> gid=<n> type=item_get key=<key> status=found size=<n>
+    pub fn fetched(&self, key: &[u8], size: Option<usize>) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let gid = self.next_gid.fetch_add(1, Ordering::Relaxed) + 1;
+        let status = match size {
+            Some(size) => format!("status=found size={}", size),
+            None => "status=not_found".to_string(),
+        };
+        let line = format!("ts={}.{:06} gid={} type=item_get key={} {}\n",
+                           now.as_secs(), now.subsec_micros(), gid, String::from_utf8_lossy(key), status);
+        self.publish(line.into_bytes());
+    }
+
+    fn publish(&self, line: Vec<u8>) {
+        for sender in self.loops.lock().unwrap().values() {
+            if let Err(e) = sender.send(Message::Watch(line.clone())) {
+                debug!("dropping a watch event; err={:?}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+extern crate remcached;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const KEYS: usize = 50;
+const ROUNDS: usize = 20;
+
+// Allocations for one multi-get of KEYS keys, averaged over ROUNDS after a
+// first round that warms up the server's buffers
+fn allocations_per_get(socket: &mut TcpStream, prefix: &str, reply_len: usize) -> usize {
+    let keys: Vec<String> = (0..KEYS).map(|key| format!("{}:{}", prefix, key)).collect();
+    let request = format!("get {}\r\n", keys.join(" ")).into_bytes();
+    let mut reply = vec![0; reply_len];
+    socket.write_all(&request).unwrap();
+    socket.read_exact(&mut reply).unwrap();
+    assert!(reply.ends_with(b"END\r\n"));
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..ROUNDS {
+        socket.write_all(&request).unwrap();
+        socket.read_exact(&mut reply).unwrap();
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) / ROUNDS
+}
+
+// Values are written from the store straight into the reply, none of them
+// is copied into an allocation of its own, so a multi-get allocates no more
+// for hits than for misses, whatever the size of the values. Replies stay
+// small enough for the server to keep their buffers.
+#[test]
+fn hits_allocate_no_more_than_misses() {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    thread::spawn(move || remcached::run(addr).expect("server failed"));
+    let started = Instant::now();
+    let mut socket = loop {
+        match TcpStream::connect(addr) {
+            Ok(socket) => break socket,
+            Err(e) if started.elapsed() > Duration::from_secs(5) => panic!("server never came up: {}", e),
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    socket.set_nodelay(true).unwrap();
+
+    let mut hit_len = b"END\r\n".len();
+    for &(prefix, size) in &[("small", 10), ("large", 500)] {
+        let value = vec![b'x'; size];
+        let mut stored = [0; 8];
+        for key in 0..KEYS {
+            let mut command = format!("set {}:{} 0 0 {}\r\n", prefix, key, size).into_bytes();
+            command.extend(&value);
+            command.extend(b"\r\n");
+            socket.write_all(&command).unwrap();
+            socket.read_exact(&mut stored).unwrap();
+            assert_eq!(&stored, b"STORED\r\n");
+        }
+        hit_len = (0..KEYS).map(|key| format!("VALUE {}:{} 0 {}\r\n", prefix, key, size).len() + size + 2).sum::<usize>() + b"END\r\n".len();
+    }
+    let small_len = (0..KEYS).map(|key| format!("VALUE small:{} 0 10\r\n", key).len() + 12).sum::<usize>() + b"END\r\n".len();
+
+    let misses = allocations_per_get(&mut socket, "none", b"END\r\n".len());
+    let small = allocations_per_get(&mut socket, "small", small_len);
+    let large = allocations_per_get(&mut socket, "large", hit_len);
+    assert!(small <= misses && large <= misses,
+            "per multi-get of {} keys: {} for misses, {} for 10 byte values, {} for 500 byte values", KEYS, misses, small, large);
+}
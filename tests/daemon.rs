@@ -0,0 +1,28 @@
+use std::env;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::process::{self, Command, Stdio};
+
+// The parent only exits once the daemon is up, with the daemon's fate
+#[test]
+fn daemonize_reports_whether_the_server_came_up() {
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_remcached"))
+        .arg("--daemonize").arg("--bind").arg(taken.local_addr().unwrap().to_string())
+        .stderr(Stdio::null())
+        .status().unwrap();
+    assert!(!status.success(), "a daemon that could not bind exited with {}", status);
+
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let pidfile = env::temp_dir().join(format!("remcached-daemon-{}.pid", process::id()));
+    let status = Command::new(env!("CARGO_BIN_EXE_remcached"))
+        .arg("--daemonize").arg("--bind").arg(addr.to_string()).arg("--pidfile").arg(&pidfile)
+        .stderr(Stdio::null())
+        .status().unwrap();
+    assert!(status.success());
+    // up by the time the parent is gone, with no retrying
+    let connected = TcpStream::connect(addr);
+    let pid = fs::read_to_string(&pidfile).unwrap();
+    Command::new("kill").arg(pid.trim()).status().unwrap();
+    assert!(connected.is_ok(), "{:?}", connected);
+}
@@ -0,0 +1,64 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn connect(path: &Path) -> UnixStream {
+    let started = Instant::now();
+    loop {
+        match UnixStream::connect(path) {
+            Ok(socket) => {
+                socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                return socket;
+            }
+            Err(e) if started.elapsed() > Duration::from_secs(5) => panic!("server never came up: {}", e),
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+fn call(mut socket: &UnixStream, request: &[u8]) -> String {
+    socket.write_all(request).unwrap();
+    let mut reader = BufReader::new(socket);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    line
+}
+
+// Running out of file descriptors fails accept, which only costs the clients
+// that could not be taken; the server keeps going
+#[test]
+fn accept_failing_for_lack_of_descriptors_keeps_the_server_up() {
+    let path = env::temp_dir().join(format!("remcached-fd-limit-{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+    let mut server = Command::new("sh")
+        .arg("-c").arg("ulimit -n 32 && exec \"$0\" --bind \"$1\"")
+        .arg(env!("CARGO_BIN_EXE_remcached")).arg(&path)
+        .env_remove("RUST_LOG")
+        .stderr(Stdio::piped())
+        .spawn().unwrap();
+
+    let first = connect(&path);
+    assert_eq!(call(&first, b"set key 0 0 5\r\nvalue\r\n"), "STORED\r\n");
+    // more clients than there are descriptors left, all queued by the kernel
+    let crowd: Vec<UnixStream> = (0..40).map(|_| UnixStream::connect(&path).unwrap()).collect();
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(call(&first, b"get key\r\n"), "VALUE key 0 5\r\n");
+
+    drop(crowd);
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+    let later = connect(&path);
+    assert_eq!(call(&later, b"delete key\r\n"), "DELETED\r\n");
+
+    server.kill().unwrap();
+    let mut log = String::new();
+    server.stderr.take().unwrap().read_to_string(&mut log).unwrap();
+    server.wait().unwrap();
+    let _ = fs::remove_file(&path);
+    assert!(log.contains("listener.accept() error"), "the limit was never hit: {}", log);
+}
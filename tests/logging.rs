@@ -0,0 +1,144 @@
+use std::char;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::iter::Peekable;
+use std::os::unix::net::UnixStream;
+use std::process::{self, Command, Stdio};
+use std::str::Chars;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The binary without RUST_LOG logs each command at info, with its latency
+#[test]
+fn commands_are_logged_by_default() {
+    let path = env::temp_dir().join(format!("remcached-logging-{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+    let mut server = Command::new(env!("CARGO_BIN_EXE_remcached"))
+        .arg("--bind").arg(&path)
+        .env_remove("RUST_LOG")
+        .stderr(Stdio::piped())
+        .spawn().unwrap();
+
+    let started = Instant::now();
+    let socket = loop {
+        match UnixStream::connect(&path) {
+            Ok(socket) => break socket,
+            Err(e) if started.elapsed() > Duration::from_secs(5) => panic!("server never came up: {}", e),
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(socket);
+    let mut line = String::new();
+    reader.get_mut().write_all(b"set key 0 0 5\r\nvalue\r\n").unwrap();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "STORED\r\n");
+
+    server.kill().unwrap();
+    let mut log = String::new();
+    server.stderr.take().unwrap().read_to_string(&mut log).unwrap();
+    server.wait().unwrap();
+    let _ = fs::remove_file(&path);
+    let command = log.lines().find(|line| line.contains("method=SET")).unwrap_or_else(|| panic!("no command logged in {:?}", log));
+    assert!(command.contains("key=key") && command.contains("bytes=5") && command.contains("latency_us="), "{}", command);
+    assert!(!command.contains("value"), "{}", command);
+}
+
+// A JSON object of string values, as the json log format writes them, or
+// why the line isn't one. Strict about escapes: a raw control character in
+// a string is an error.
+fn parse_json_line(line: &str) -> Result<Vec<(String, String)>, String> {
+    fn string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+        if chars.next() != Some('"') {
+            return Err("expected a string".to_string());
+        }
+        let mut out = String::new();
+        loop {
+            match chars.next() {
+                None => return Err("unterminated string".to_string()),
+                Some('"') => return Ok(out),
+                Some('\\') => match chars.next() {
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('/') => out.push('/'),
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let code = u32::from_str_radix(&hex, 16).map_err(|e| format!("bad \\u escape {:?}: {}", hex, e))?;
+                        out.push(char::from_u32(code).ok_or("bad \\u escape")?);
+                    }
+                    other => return Err(format!("bad escape {:?}", other)),
+                },
+                Some(c) if (c as u32) < 0x20 => return Err(format!("raw control character {:?}", c)),
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    let mut chars = line.chars().peekable();
+    let mut fields = Vec::new();
+    if chars.next() != Some('{') {
+        return Err("expected an object".to_string());
+    }
+    loop {
+        let name = string(&mut chars)?;
+        if chars.next() != Some(':') {
+            return Err("expected a colon".to_string());
+        }
+        fields.push((name, string(&mut chars)?));
+        match chars.next() {
+            Some(',') => {}
+            Some('}') if chars.peek().is_none() => return Ok(fields),
+            other => return Err(format!("unexpected {:?}", other)),
+        }
+    }
+}
+
+// Every line is an object of its own, whatever ends up in the messages
+#[test]
+fn json_log_lines_are_valid_json() {
+    let path = env::temp_dir().join(format!("remcached \"quoted\"\nnext\tline\u{1}\\{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+    let mut server = Command::new(env!("CARGO_BIN_EXE_remcached"))
+        .arg("--bind").arg(&path).arg("--log-format").arg("json")
+        .env_remove("RUST_LOG")
+        .stderr(Stdio::piped())
+        .spawn().unwrap();
+
+    let started = Instant::now();
+    let socket = loop {
+        match UnixStream::connect(&path) {
+            Ok(socket) => break socket,
+            Err(e) if started.elapsed() > Duration::from_secs(5) => panic!("server never came up: {}", e),
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(socket);
+    let mut line = String::new();
+    reader.get_mut().write_all(b"set key 0 0 5\r\nvalue\r\n").unwrap();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "STORED\r\n");
+
+    server.kill().unwrap();
+    let mut log = String::new();
+    server.stderr.take().unwrap().read_to_string(&mut log).unwrap();
+    server.wait().unwrap();
+    let _ = fs::remove_file(&path);
+
+    let lines: Vec<Vec<(String, String)>> = log.lines()
+        .map(|line| parse_json_line(line).unwrap_or_else(|e| panic!("{}: {:?}", e, line)))
+        .collect();
+    let field = |fields: &[(String, String)], name: &str| fields.iter().find(|field| field.0 == name).map(|field| field.1.clone());
+    // the socket path comes back exactly as it went in
+    let path = path.display().to_string();
+    assert!(lines.iter().any(|fields| field(fields, "msg").is_some_and(|msg| msg.ends_with(&path))), "path missing from {}", log);
+    // a client address with a space in it keeps the command's message whole
+    assert!(lines.iter().any(|fields| field(fields, "msg").is_some_and(|msg| msg.contains(&path) && msg.contains("method=SET key=key"))),
+            "no command logged in {}", log);
+}
@@ -0,0 +1,1178 @@
+extern crate libc;
+extern crate remcached;
+extern crate rustls;
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use remcached::{Address, Config, Item, Shard, Store};
+
+// Every test gets a server of its own on a free port of 127.0.0.1, found by
+// letting the kernel pick one. The server thread lives until the test binary
+// exits and leaves nothing behind.
+fn start(config: Config) -> Client {
+    let addr = free_addr();
+    thread::spawn(move || remcached::serve(Config { bind: Address::Tcp(addr), ..config }).expect("server failed"));
+    Client::connect(addr)
+}
+
+// Like start, with the items kept in the given store
+fn start_with<S: Store>(config: Config, store: S) -> Client {
+    let addr = free_addr();
+    thread::spawn(move || remcached::serve_with(Config { bind: Address::Tcp(addr), ..config }, store).expect("server failed"));
+    Client::connect(addr)
+}
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+struct Client {
+    addr: SocketAddr,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Client {
+        let started = Instant::now();
+        loop {
+            match TcpStream::connect(addr) {
+                Ok(socket) => {
+                    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                    // commands written in pieces have to arrive in pieces
+                    socket.set_nodelay(true).unwrap();
+                    return Client { addr, reader: BufReader::new(socket) };
+                }
+                Err(e) if started.elapsed() > Duration::from_secs(5) => panic!("server never came up: {}", e),
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    // Another client of the same server
+    fn another(&self) -> Client {
+        Client::connect(self.addr)
+    }
+
+    fn send(&mut self, request: &[u8]) {
+        self.reader.get_mut().write_all(request).unwrap();
+    }
+
+    // Whether the server closed the connection without a word
+    fn closed(&mut self) -> bool {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap() == 0
+    }
+
+    fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "unterminated reply {:?}", line);
+        line.truncate(line.len() - 2);
+        line
+    }
+
+    // Sends one command and reads back a one line reply
+    fn call(&mut self, request: &str) -> String {
+        self.send(request.as_bytes());
+        self.line()
+    }
+
+    fn set(&mut self, key: &str, flags: u32, value: &[u8]) -> String {
+        let mut request = format!("set {} {} 0 {}\r\n", key, flags, value.len()).into_bytes();
+        request.extend(value);
+        request.extend(b"\r\n");
+        self.send(&request);
+        self.line()
+    }
+
+    // The flags and value of a key, or None on a miss
+    fn get(&mut self, key: &str) -> Option<(u32, Vec<u8>)> {
+        self.send(format!("get {}\r\n", key).as_bytes());
+        let header = self.line();
+        if header == "END" {
+            return None;
+        }
+        let fields: Vec<&str> = header.split(' ').collect();
+        assert_eq!(fields.len(), 4, "bad VALUE line {:?}", header);
+        assert_eq!((fields[0], fields[1]), ("VALUE", key));
+        let flags = fields[2].parse().unwrap();
+        let mut value = vec![0; fields[3].parse::<usize>().unwrap() + 2];
+        self.reader.read_exact(&mut value).unwrap();
+        assert!(value.ends_with(b"\r\n"));
+        value.truncate(value.len() - 2);
+        assert_eq!(self.line(), "END");
+        Some((flags, value))
+    }
+
+    // The STAT lines of a stats command, by name
+    fn stats(&mut self, request: &str) -> HashMap<String, String> {
+        self.send(request.as_bytes());
+        let mut stats = HashMap::new();
+        loop {
+            let line = self.line();
+            if line == "END" {
+                return stats;
+            }
+            let mut fields = line.splitn(3, ' ');
+            assert_eq!(fields.next(), Some("STAT"), "bad STAT line {:?}", line);
+            let name = fields.next().unwrap().to_string();
+            stats.insert(name, fields.next().unwrap_or_default().to_string());
+        }
+    }
+}
+
+#[test]
+fn set_then_get() {
+    // the plain entry point, with everything else left at the defaults
+    let addr = free_addr();
+    thread::spawn(move || remcached::run(addr).expect("server failed"));
+    let mut client = Client::connect(addr);
+    assert_eq!(client.set("greeting", 7, b"hello world"), "STORED");
+    assert_eq!(client.get("greeting"), Some((7, b"hello world".to_vec())));
+}
+
+#[test]
+fn get_missing_key() {
+    let mut client = start(Config::default());
+    assert_eq!(client.get("nothing"), None);
+}
+
+#[test]
+fn set_overwrites() {
+    let mut client = start(Config::default());
+    assert_eq!(client.set("key", 1, b"first"), "STORED");
+    assert_eq!(client.set("key", 2, b"second"), "STORED");
+    assert_eq!(client.get("key"), Some((2, b"second".to_vec())));
+}
+
+#[test]
+fn binary_safe_values() {
+    let mut client = start(Config::default());
+    let value: Vec<u8> = (0..=255).chain(b"\r\nEND\r\n".iter().cloned()).collect();
+    assert_eq!(client.set("bytes", 0, &value), "STORED");
+    assert_eq!(client.get("bytes"), Some((0, value)));
+}
+
+#[test]
+fn delete_removes() {
+    let mut client = start(Config::default());
+    assert_eq!(client.set("key", 0, b"value"), "STORED");
+    assert_eq!(client.call("delete key\r\n"), "DELETED");
+    assert_eq!(client.get("key"), None);
+    assert_eq!(client.call("delete key\r\n"), "NOT_FOUND");
+}
+
+#[test]
+fn clients_share_the_cache() {
+    let mut writer = start(Config::default());
+    assert_eq!(writer.set("shared", 3, b"value"), "STORED");
+    let mut reader = writer.another();
+    assert_eq!(reader.get("shared"), Some((3, b"value".to_vec())));
+}
+
+#[test]
+fn unknown_command() {
+    let mut client = start(Config::default());
+    assert_eq!(client.call("bogus\r\n"), "ERROR");
+}
+
+#[test]
+fn negative_exptime_is_already_expired() {
+    let mut client = start(Config::default());
+    assert_eq!(client.call("set gone 0 -1 5\r\nvalue\r\n"), "STORED");
+    assert_eq!(client.get("gone"), None);
+    assert_eq!(client.call("set gone 0 -1000 5\r\nvalue\r\n"), "STORED");
+    assert_eq!(client.get("gone"), None);
+    // touching with one expires an item that was there
+    assert_eq!(client.set("touched", 0, b"value"), "STORED");
+    assert_eq!(client.call("touch touched -1\r\n"), "TOUCHED");
+    assert_eq!(client.get("touched"), None);
+}
+
+// Sends a set whose data block is far larger than the read buffer, a piece
+// at a time
+fn set_large(client: &mut Client, key: &str, len: usize) -> String {
+    client.send(format!("set {} 0 0 {}\r\n", key, len).as_bytes());
+    send_filler(client, len);
+    client.send(b"\r\n");
+    client.line()
+}
+
+fn send_filler(client: &mut Client, len: usize) {
+    let chunk = vec![b'x'; 64 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(chunk.len());
+        client.send(&chunk[..n]);
+        left -= n;
+    }
+}
+
+#[test]
+fn read_only_refuses_without_buffering_the_value() {
+    let mut client = start(Config { read_only: true, max_read_buffer: 4096, ..Config::default() });
+    // the data block would overflow the read buffer if it were kept
+    assert_eq!(set_large(&mut client, "big", 1024 * 1024), "SERVER_ERROR read-only");
+    assert_eq!(client.get("big"), None);
+    assert_eq!(client.call("set small 0 0 1 noreply\r\nx\r\nversion\r\n"), format!("VERSION {}", env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn too_large_value_keeps_the_connection() {
+    let mut client = start(Config { max_item_size: 1024, max_read_buffer: 4096, ..Config::default() });
+    assert_eq!(set_large(&mut client, "big", 1024 * 1024), "SERVER_ERROR object too large for cache");
+    assert_eq!(client.set("small", 0, b"value"), "STORED");
+    assert_eq!(client.get("small"), Some((0, b"value".to_vec())));
+}
+
+#[test]
+fn meta_noop_ends_a_pipeline() {
+    let mut client = start(Config::default());
+    client.send(b"ms one 3\r\nabc\r\nms two 2\r\nde\r\nmg one v\r\nmg missing v\r\nmg two v\r\nmn\r\n");
+    let mut replies = Vec::new();
+    loop {
+        let line = client.line();
+        if line == "MN" {
+            break;
+        }
+        replies.push(line);
+    }
+    assert_eq!(replies, ["HD", "HD", "VA 3", "abc", "EN", "VA 2", "de"]);
+}
+
+// A single shard store that writes down every call made to it
+#[derive(Default)]
+struct FakeStore {
+    shard: Mutex<FakeShard>,
+}
+
+#[derive(Default)]
+struct FakeShard {
+    items: HashMap<Vec<u8>, Item>,
+    calls: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeShard {
+    fn record(&self, call: &str, key: &[u8]) {
+        self.calls.lock().unwrap().push(format!("{} {}", call, String::from_utf8_lossy(key)));
+    }
+}
+
+impl Shard for FakeShard {
+    // looking up `slow` holds up the event loop like a flood of work would
+    fn get(&mut self, key: &[u8]) -> Option<&mut Item> {
+        self.record("get", key);
+        if key == b"slow" {
+            thread::sleep(Duration::from_millis(300));
+        }
+        self.items.get_mut(key)
+    }
+
+    fn peek(&self, key: &[u8]) -> Option<&Item> {
+        self.record("peek", key);
+        self.items.get(key)
+    }
+
+    fn set(&mut self, key: Vec<u8>, item: Item) {
+        self.record("set", &key);
+        self.items.insert(key, item);
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Item> {
+        self.record("remove", key);
+        self.items.remove(key)
+    }
+
+    fn can_hold(&self, _key: &[u8], _value_len: usize) -> bool {
+        true
+    }
+
+    fn resize(&mut self, key: &[u8]) {
+        self.record("resize", key);
+    }
+}
+
+impl Store for FakeStore {
+    type Shard = FakeShard;
+
+    fn shard(&self, _key: &[u8]) -> MutexGuard<'_, FakeShard> {
+        self.shard.lock().unwrap()
+    }
+
+    fn shard_count(&self) -> usize {
+        1
+    }
+
+    fn for_each_in_shard<F>(&self, _index: usize, mut f: F) where F: FnMut(&[u8], &Item, Duration, u64) {
+        for (key, item) in &self.shard.lock().unwrap().items {
+            f(key, item, Duration::from_secs(0), 0);
+        }
+    }
+
+    fn max_item_size(&self) -> usize {
+        1024
+    }
+
+    fn len(&self) -> usize {
+        self.shard.lock().unwrap().items.len()
+    }
+
+    fn bytes(&self) -> usize {
+        0
+    }
+
+    fn memory_limit(&self) -> usize {
+        0
+    }
+
+    fn set_memory_limit(&self, _memory_limit: usize) {}
+
+    fn clear(&self) {
+        self.shard.lock().unwrap().items.clear();
+    }
+}
+
+#[test]
+fn commands_go_through_the_given_store() {
+    let store = FakeStore::default();
+    let calls = store.shard.lock().unwrap().calls.clone();
+    let mut client = start_with(Config::default(), store);
+    assert_eq!(client.set("key", 0, b"value"), "STORED");
+    assert_eq!(client.get("key"), Some((0, b"value".to_vec())));
+    assert_eq!(client.call("append key 0 0 1\r\n!\r\n"), "STORED");
+    assert_eq!(client.call("delete key\r\n"), "DELETED");
+    assert_eq!(client.get("key"), None);
+    assert_eq!(*calls.lock().unwrap(), ["set key", "get key", "peek key", "get key", "resize key", "get key", "remove key", "get key"]);
+}
+
+#[test]
+fn overloaded_server_turns_new_clients_away() {
+    let mut client = start_with(Config { overload_threshold: 100, ..Config::default() }, FakeStore::default());
+    client.send(b"get slow\r\n");
+    // connects while the loop is stuck, gets accepted once the slow pass is over
+    thread::sleep(Duration::from_millis(100));
+    let mut turned_away = client.another();
+    assert_eq!(client.line(), "END");
+    assert!(turned_away.closed());
+    // a quick pass later the loop has caught up
+    assert_eq!(client.get("key"), None);
+    let mut later = client.another();
+    assert_eq!(later.get("key"), None);
+}
+
+#[test]
+fn exptime_past_thirty_days_is_a_unix_timestamp() {
+    let mut client = start(Config::default());
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    assert_eq!(client.call(&format!("set soon 0 {} 5\r\nvalue\r\n", now + 5)), "STORED");
+    assert_eq!(client.get("soon"), Some((0, b"value".to_vec())));
+    assert_eq!(client.call(&format!("set past 0 {} 5\r\nvalue\r\n", now - 5)), "STORED");
+    assert_eq!(client.get("past"), None);
+}
+
+#[test]
+fn unix_socket_gets_the_requested_mode() {
+    let path = env::temp_dir().join(format!("remcached-test-{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+    let config = Config { bind: Address::Unix(path.clone()), unix_mode: Some(0o600), ..Config::default() };
+    thread::spawn(move || remcached::serve(config).expect("server failed"));
+    // as soon as there is anything at the path at all
+    let started = Instant::now();
+    let mode = loop {
+        if let Ok(metadata) = fs::metadata(&path) {
+            break metadata.permissions().mode();
+        }
+        assert!(started.elapsed() < Duration::from_secs(5), "server never came up");
+        thread::yield_now();
+    };
+    assert_eq!(mode & 0o777, 0o600);
+    UnixStream::connect(&path).unwrap();
+    // and nothing left next to it
+    let name = path.file_name().unwrap().to_str().unwrap().to_string();
+    let leftovers: Vec<String> = fs::read_dir(env::temp_dir()).unwrap()
+        .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+        .filter(|entry| *entry != name && entry.contains(&name))
+        .collect();
+    fs::remove_file(&path).unwrap();
+    assert!(leftovers.is_empty(), "{:?}", leftovers);
+}
+
+// The fields of the metadump line of a key
+fn metadump(client: &mut Client, key: &str) -> HashMap<String, String> {
+    client.send(b"lru_crawler metadump all\r\n");
+    let mut found = HashMap::new();
+    loop {
+        let line = client.line();
+        if line == "END" {
+            return found;
+        }
+        let fields: HashMap<String, String> = line.split(' ')
+            .filter_map(|field| field.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        if fields.get("key").map(String::as_str) == Some(key) {
+            found = fields;
+        }
+    }
+}
+
+#[test]
+fn metadump_shows_fetches_until_the_next_set() {
+    let mut client = start(Config::default());
+    assert_eq!(client.set("key", 0, b"value"), "STORED");
+    assert_eq!(metadump(&mut client, "key")["fetch"], "no");
+    client.get("key");
+    client.get("key");
+    let fields = metadump(&mut client, "key");
+    assert_eq!(fields["fetch"], "yes");
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let last_access: u64 = fields["la"].parse().unwrap();
+    assert!(last_access + 2 >= now && last_access <= now + 2, "la={} now={}", last_access, now);
+    assert_eq!(client.set("key", 0, b"again"), "STORED");
+    assert_eq!(metadump(&mut client, "key")["fetch"], "no");
+    // looking the key up without handing out its value is no fetch
+    assert_eq!(client.call("append key 0 0 1\r\n!\r\n"), "STORED");
+    assert_eq!(client.call("touch key 0\r\n"), "TOUCHED");
+    assert_eq!(client.call("mg key t\r\n"), "HD t-1");
+    assert_eq!(metadump(&mut client, "key")["fetch"], "no");
+    assert_eq!(client.call("mg key v\r\n"), "VA 6");
+    assert_eq!(client.line(), "again!");
+    assert_eq!(metadump(&mut client, "key")["fetch"], "yes");
+}
+
+#[test]
+fn verb_split_across_writes() {
+    let mut client = start(Config::default());
+    assert_eq!(client.set("foo", 0, b"bar"), "STORED");
+    let mut split = client.another();
+    split.send(b"ge");
+    thread::sleep(Duration::from_millis(50));
+    split.send(b"t foo\r\n");
+    assert_eq!(split.line(), "VALUE foo 0 3");
+    assert_eq!(split.line(), "bar");
+    assert_eq!(split.line(), "END");
+}
+
+#[test]
+fn short_set_takes_the_default_flags_and_exptime() {
+    let mut client = start(Config { default_flags: 42, default_exptime: 1, ..Config::default() });
+    assert_eq!(client.call("set short value\r\n"), "STORED");
+    assert_eq!(client.get("short"), Some((42, b"value".to_vec())));
+    // under a second left of the one, which the whole seconds of t round down
+    assert_eq!(client.call("mg short t\r\n"), "HD t0");
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(client.get("short"), None);
+    // the usual form still has flags and exptime of its own
+    assert_eq!(client.call("set long 7 0 5\r\nvalue\r\n"), "STORED");
+    assert_eq!(client.get("long"), Some((7, b"value".to_vec())));
+    // neither form
+    assert_eq!(client.call("set bad value more\r\n"), "CLIENT_ERROR bad command line format");
+}
+
+#[test]
+fn cas_against_an_expired_key_is_not_found() {
+    let mut client = start(Config::default());
+    assert_eq!(client.call("set fresh 0 1 3\r\nold\r\n"), "STORED");
+    assert_eq!(client.call("set stale 0 1 3\r\nold\r\n"), "STORED");
+    let header = client.call("gets fresh\r\n");
+    let token: u64 = header.rsplit(' ').next().unwrap().parse().unwrap();
+    assert_eq!(client.line(), "old");
+    assert_eq!(client.line(), "END");
+    thread::sleep(Duration::from_millis(1100));
+    // neither the right token nor a wrong one matches what has expired
+    assert_eq!(client.call(&format!("cas fresh 0 0 3 {}\r\nnew\r\n", token)), "NOT_FOUND");
+    assert_eq!(client.call(&format!("cas stale 0 0 3 {}\r\nnew\r\n", token + 1000)), "NOT_FOUND");
+    assert_eq!(client.get("fresh"), None);
+}
+
+#[test]
+fn overlong_command_line_closes_the_connection() {
+    let mut client = start(Config::default());
+    let mut line = b"get ".to_vec();
+    line.resize(16 * 1024, b'k');
+    client.send(&line);
+    assert_eq!(client.line(), "ERROR");
+    assert!(client.closed());
+    // a line of a sensible length is still fine
+    let mut client = client.another();
+    assert_eq!(client.set("key", 0, b"value"), "STORED");
+}
+
+#[test]
+fn flush_all_delay_out_of_range_is_refused() {
+    let mut client = start(Config::default());
+    assert_eq!(client.set("key", 0, b"value"), "STORED");
+    assert_eq!(client.call("flush_all 18446744073709551615\r\n"), "CLIENT_ERROR invalid exptime argument");
+    assert_eq!(client.call("flush_all 2592001\r\n"), "CLIENT_ERROR invalid exptime argument");
+    // the server is still up and nothing was flushed
+    assert_eq!(client.get("key"), Some((0, b"value".to_vec())));
+    assert_eq!(client.call("flush_all 2592000\r\n"), "OK");
+    assert_eq!(client.get("key"), Some((0, b"value".to_vec())));
+    assert_eq!(client.call("flush_all\r\n"), "OK");
+    assert_eq!(client.get("key"), None);
+}
+
+#[test]
+fn value_within_the_memory_limit_is_stored() {
+    // 1 MB for the whole store, far more than the value, though a shard's
+    // share of it would be less
+    let mut client = start(Config { memory_limit: 1 << 20, max_item_size: 4 << 20, max_read_buffer: 4 << 20, ..Config::default() });
+    assert_eq!(set_large(&mut client, "fits", 200 * 1024), "STORED");
+    assert_eq!(client.get("fits").map(|(_, value)| value.len()), Some(200 * 1024));
+    assert_eq!(set_large(&mut client, "never", 2 << 20), "SERVER_ERROR out of memory storing object");
+}
+
+#[test]
+fn multi_get_may_ask_for_up_to_max_keys() {
+    let mut client = start(Config { max_keys: 3, ..Config::default() });
+    assert_eq!(client.set("a", 0, b"1"), "STORED");
+    assert_eq!(client.call("get a b c\r\n"), "VALUE a 0 1");
+    assert_eq!(client.line(), "1");
+    assert_eq!(client.line(), "END");
+    assert_eq!(client.call("get a b c d\r\n"), "CLIENT_ERROR too many keys");
+    // the rest of the line went with it
+    assert_eq!(client.get("a"), Some((0, b"1".to_vec())));
+}
+
+#[test]
+fn connections_up_to_the_cap_are_served() {
+    let mut first = start(Config { max_connections: 2, ..Config::default() });
+    let mut second = first.another();
+    assert_eq!(first.set("key", 0, b"value"), "STORED");
+    assert_eq!(second.get("key"), Some((0, b"value".to_vec())));
+    let mut third = first.another();
+    assert!(third.closed());
+    // a place opens up once one of them goes away
+    drop(second);
+    thread::sleep(Duration::from_millis(100));
+    let mut fourth = first.another();
+    assert_eq!(fourth.get("key"), Some((0, b"value".to_vec())));
+}
+
+#[test]
+fn incr_and_decr_over_the_wire() {
+    let mut client = start(Config::default());
+    assert_eq!(client.set("n", 0, b"18446744073709551614"), "STORED");
+    assert_eq!(client.call("incr n 1\r\n"), "18446744073709551615");
+    assert_eq!(client.call("incr n 2\r\n"), "1");
+    // the value shrinks with its digits rather than keeping the old width
+    assert_eq!(client.get("n"), Some((0, b"1".to_vec())));
+    assert_eq!(client.call("decr n 5\r\n"), "0");
+    // one past the largest 64 bit number is no number at all
+    assert_eq!(client.set("big", 0, b"18446744073709551616"), "STORED");
+    assert_eq!(client.call("incr big 1\r\n"), "CLIENT_ERROR cannot increment or decrement non-numeric value");
+    assert_eq!(client.call("incr n 18446744073709551616\r\n"), "CLIENT_ERROR invalid numeric delta argument");
+    assert_eq!(client.get("big"), Some((0, b"18446744073709551616".to_vec())));
+}
+
+#[test]
+fn add_only_stores_a_missing_key() {
+    let mut client = start(Config::default());
+    assert_eq!(client.call("add key 1 0 5\r\nfirst\r\n"), "STORED");
+    assert_eq!(client.call("add key 2 0 6\r\nsecond\r\n"), "NOT_STORED");
+    assert_eq!(client.get("key"), Some((1, b"first".to_vec())));
+    // an expired key counts as missing
+    assert_eq!(client.call("set gone 0 -1 3\r\nold\r\n"), "STORED");
+    assert_eq!(client.call("add gone 0 0 3\r\nnew\r\n"), "STORED");
+    assert_eq!(client.get("gone"), Some((0, b"new".to_vec())));
+}
+
+#[test]
+fn replace_only_stores_an_existing_key() {
+    let mut client = start(Config::default());
+    assert_eq!(client.call("replace key 0 0 5\r\nfirst\r\n"), "NOT_STORED");
+    assert_eq!(client.get("key"), None);
+    assert_eq!(client.set("key", 1, b"first"), "STORED");
+    assert_eq!(client.call("replace key 2 0 6\r\nsecond\r\n"), "STORED");
+    assert_eq!(client.get("key"), Some((2, b"second".to_vec())));
+}
+
+#[test]
+fn client_resetting_mid_reply_only_loses_its_own_connection() {
+    let mut client = start(Config { max_connections: 2, ..Config::default() });
+    assert_eq!(set_large(&mut client, "big", 900 * 1024), "STORED");
+    let mut resetting = client.another();
+    // replies far larger than the socket buffers, none of them read
+    resetting.send(b"get big\r\nget big\r\nget big\r\nget big\r\n");
+    thread::sleep(Duration::from_millis(100));
+    // a zero linger turns the close into a reset
+    let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+    let fd = resetting.reader.get_ref().as_raw_fd();
+    let set = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_LINGER, &linger as *const libc::linger as *const libc::c_void,
+                         std::mem::size_of::<libc::linger>() as libc::socklen_t)
+    };
+    assert_eq!(set, 0);
+    drop(resetting);
+    thread::sleep(Duration::from_millis(100));
+    // the server is still up and the reset connection's place is free again
+    assert_eq!(client.call("delete big\r\n"), "DELETED");
+    let mut next = client.another();
+    assert_eq!(next.set("key", 0, b"value"), "STORED");
+}
+
+#[test]
+fn client_at_eof_gets_its_replies_and_frees_its_place() {
+    let mut client = start(Config { max_connections: 1, ..Config::default() });
+    // writes, then closes its side while the replies are still to come
+    client.send(b"set key 0 0 5\r\nvalue\r\nget key\r\n");
+    client.reader.get_ref().shutdown(Shutdown::Write).unwrap();
+    let mut replies = String::new();
+    client.reader.read_to_string(&mut replies).unwrap();
+    assert_eq!(replies, "STORED\r\nVALUE key 0 5\r\nvalue\r\nEND\r\n");
+    drop(client.reader);
+    // the only place there is goes to the next client
+    thread::sleep(Duration::from_millis(100));
+    let mut next = Client::connect(client.addr);
+    assert_eq!(next.get("key"), Some((0, b"value".to_vec())));
+}
+
+#[test]
+fn delayed_flush_all_clears_once_the_delay_is_over() {
+    let mut client = start(Config::default());
+    assert_eq!(client.set("key", 0, b"value"), "STORED");
+    assert_eq!(client.call("flush_all 1\r\n"), "OK");
+    assert_eq!(client.get("key"), Some((0, b"value".to_vec())));
+    thread::sleep(Duration::from_millis(1300));
+    assert_eq!(client.get("key"), None);
+    // items stored after the flush ran are kept
+    assert_eq!(client.set("key", 0, b"again"), "STORED");
+    assert_eq!(client.get("key"), Some((0, b"again".to_vec())));
+}
+
+#[test]
+fn pipelined_commands_are_all_answered_in_order() {
+    let mut client = start(Config::default());
+    // two whole commands and the start of a third in a single write
+    client.send(b"set a 0 0 1\r\nx\r\nget a\r\nset b 0 0 3\r\nab");
+    assert_eq!(client.line(), "STORED");
+    assert_eq!(client.line(), "VALUE a 0 1");
+    assert_eq!(client.line(), "x");
+    assert_eq!(client.line(), "END");
+    // the rest of the third stayed buffered
+    assert_eq!(client.call("c\r\n"), "STORED");
+    assert_eq!(client.get("b"), Some((0, b"abc".to_vec())));
+}
+
+#[test]
+fn touch_sets_a_new_exptime() {
+    let mut client = start(Config::default());
+    assert_eq!(client.call("touch missing 10\r\n"), "NOT_FOUND");
+    assert_eq!(client.set("shortened", 0, b"value"), "STORED");
+    assert_eq!(client.call("touch shortened 1\r\n"), "TOUCHED");
+    assert_eq!(client.call("set kept 0 1 5\r\nvalue\r\n"), "STORED");
+    assert_eq!(client.call("touch kept 0\r\n"), "TOUCHED");
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(client.get("shortened"), None);
+    assert_eq!(client.get("kept"), Some((0, b"value".to_vec())));
+}
+
+#[test]
+fn idle_client_is_disconnected() {
+    let mut idle = start(Config { idle_timeout: 1, ..Config::default() });
+    let mut busy = idle.another();
+    let started = Instant::now();
+    // activity keeps pushing the deadline out
+    for _ in 0..4 {
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(busy.get("key"), None);
+    }
+    assert!(idle.closed());
+    assert!(started.elapsed() < Duration::from_secs(3), "closed after {:?}", started.elapsed());
+    assert_eq!(busy.get("key"), None);
+}
+
+#[test]
+fn client_that_stops_reading_is_dropped_after_the_write_timeout() {
+    let mut client = start(Config { write_timeout: 1, ..Config::default() });
+    assert_eq!(set_large(&mut client, "big", 900 * 1024), "STORED");
+    let mut stalled = client.another();
+    // far more than the socket buffers hold, and none of it read for now
+    let replies = 8;
+    for _ in 0..replies {
+        stalled.send(b"get big\r\n");
+    }
+    thread::sleep(Duration::from_millis(2500));
+    // what made it into the socket buffers arrives, then the connection ends
+    let mut received = Vec::new();
+    let _ = stalled.reader.read_to_end(&mut received);
+    assert!(received.len() < replies * 900 * 1024, "all {} bytes arrived", received.len());
+    assert_eq!(client.call("delete big\r\n"), "DELETED");
+}
+
+// A binary protocol request: opcode, opaque, cas, extras, key and value
+fn binary_request(opcode: u8, opaque: u32, cas: u64, extras: &[u8], key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut request = vec![0x80, opcode];
+    request.extend(&(key.len() as u16).to_be_bytes());
+    request.extend(&[extras.len() as u8, 0, 0, 0]);
+    request.extend(&((extras.len() + key.len() + value.len()) as u32).to_be_bytes());
+    request.extend(&opaque.to_be_bytes());
+    request.extend(&cas.to_be_bytes());
+    request.extend(extras);
+    request.extend(key);
+    request.extend(value);
+    request
+}
+
+struct BinaryReply {
+    opcode: u8,
+    status: u16,
+    opaque: u32,
+    cas: u64,
+    extras: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl Client {
+    fn binary_reply(&mut self) -> BinaryReply {
+        let mut header = [0; 24];
+        self.reader.read_exact(&mut header).unwrap();
+        assert_eq!(header[0], 0x81, "not a binary reply");
+        let extras_len = header[4] as usize;
+        let body_len = u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize;
+        let mut body = vec![0; body_len];
+        self.reader.read_exact(&mut body).unwrap();
+        let mut cas = [0; 8];
+        cas.copy_from_slice(&header[16..]);
+        BinaryReply {
+            opcode: header[1],
+            status: u16::from_be_bytes([header[6], header[7]]),
+            opaque: u32::from_be_bytes([header[12], header[13], header[14], header[15]]),
+            cas: u64::from_be_bytes(cas),
+            extras: body[..extras_len].to_vec(),
+            value: body[extras_len..].to_vec(),
+        }
+    }
+}
+
+#[test]
+fn binary_set_get_and_delete() {
+    let mut client = start(Config::default());
+    let extras = [0, 0, 0, 7, 0, 0, 0, 0];
+    client.send(&binary_request(0x01, 11, 0, &extras, b"key", b"value"));
+    let set = client.binary_reply();
+    assert_eq!((set.opcode, set.status, set.opaque), (0x01, 0x0000, 11));
+    assert!(set.cas != 0);
+
+    client.send(&binary_request(0x00, 12, 0, &[], b"key", &[]));
+    let get = client.binary_reply();
+    assert_eq!((get.opcode, get.status, get.opaque, get.cas), (0x00, 0x0000, 12, set.cas));
+    assert_eq!((get.extras, get.value), (vec![0, 0, 0, 7], b"value".to_vec()));
+
+    client.send(&binary_request(0x04, 13, 0, &[], b"key", &[]));
+    let delete = client.binary_reply();
+    assert_eq!((delete.opcode, delete.status, delete.opaque), (0x04, 0x0000, 13));
+
+    client.send(&binary_request(0x00, 14, 0, &[], b"key", &[]));
+    let miss = client.binary_reply();
+    assert_eq!((miss.status, miss.opaque), (0x0001, 14));
+}
+
+#[test]
+fn binary_cas_mismatch_is_key_exists() {
+    let mut client = start(Config::default());
+    let extras = [0; 8];
+    client.send(&binary_request(0x01, 1, 0, &extras, b"key", b"first"));
+    let stored = client.binary_reply();
+    client.send(&binary_request(0x01, 2, stored.cas + 1, &extras, b"key", b"second"));
+    assert_eq!(client.binary_reply().status, 0x0002);
+    client.send(&binary_request(0x01, 3, stored.cas, &extras, b"key", b"third"));
+    assert_eq!(client.binary_reply().status, 0x0000);
+    client.send(&binary_request(0x00, 4, 0, &[], b"key", &[]));
+    assert_eq!(client.binary_reply().value, b"third".to_vec());
+}
+
+#[test]
+fn binary_unknown_opcode_keeps_the_connection() {
+    let mut client = start(Config::default());
+    client.send(&binary_request(0x3f, 21, 0, &[], b"key", &[]));
+    let unknown = client.binary_reply();
+    assert_eq!((unknown.opcode, unknown.status, unknown.opaque), (0x3f, 0x0081, 21));
+    client.send(&binary_request(0x00, 22, 0, &[], b"key", &[]));
+    assert_eq!(client.binary_reply().status, 0x0001);
+}
+
+#[test]
+fn binary_body_shorter_than_its_key_is_refused_in_binary() {
+    let mut client = start(Config::default());
+    let mut request = binary_request(0x00, 31, 0, &[], b"key", &[]);
+    // a body length that can't even hold the key
+    request[8..12].copy_from_slice(&1u32.to_be_bytes());
+    client.send(&request);
+    let refused = client.binary_reply();
+    assert_eq!((refused.status, refused.opaque), (0x0004, 31));
+    assert!(client.closed());
+}
+
+#[test]
+fn binary_body_past_the_read_buffer_is_refused_in_binary() {
+    let mut client = start(Config { max_read_buffer: 4096, ..Config::default() });
+    let mut request = binary_request(0x01, 41, 0, &[0; 8], b"key", &[]);
+    request[8..12].copy_from_slice(&(1u32 << 20).to_be_bytes());
+    client.send(&request);
+    let refused = client.binary_reply();
+    assert_eq!((refused.opcode, refused.status, refused.opaque), (0x01, 0x0003, 41));
+    assert!(client.closed());
+}
+
+// A self-signed certificate for localhost, made with
+// openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -days 36500
+//     -subj /CN=localhost -addext subjectAltName=DNS:localhost -addext basicConstraints=critical,CA:FALSE
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+fn tls_connect(addr: SocketAddr) -> BufReader<rustls::StreamOwned<rustls::ClientConnection, TcpStream>> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use std::convert::TryFrom;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_file(fixture("cert.pem")).unwrap()).unwrap();
+    let config = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    let session = rustls::ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+    let socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    BufReader::new(rustls::StreamOwned::new(session, socket))
+}
+
+#[test]
+fn tls_client_sets_and_gets() {
+    let plain = start(Config { tls_cert: Some(fixture("cert.pem")), tls_key: Some(fixture("key.pem")), ..Config::default() });
+    let mut client = tls_connect(plain.addr);
+    client.get_mut().write_all(b"set key 3 0 5\r\nvalue\r\nget key\r\n").unwrap();
+    let mut lines = Vec::new();
+    for _ in 0..4 {
+        let mut line = String::new();
+        client.read_line(&mut line).unwrap();
+        lines.push(line);
+    }
+    assert_eq!(lines, ["STORED\r\n", "VALUE key 3 5\r\n", "value\r\n", "END\r\n"]);
+}
+
+#[test]
+fn plaintext_client_on_the_tls_port_is_dropped() {
+    let mut plain = start(Config { tls_cert: Some(fixture("cert.pem")), tls_key: Some(fixture("key.pem")), ..Config::default() });
+    plain.send(b"set key 0 0 5\r\nvalue\r\n");
+    // at most a tls alert comes back, never a text reply
+    let mut received = Vec::new();
+    plain.reader.read_to_end(&mut received).unwrap();
+    assert!(!received.windows(6).any(|w| w == b"STORED"), "got {:?}", received);
+
+    let mut client = tls_connect(plain.addr);
+    client.get_mut().write_all(b"get key\r\n").unwrap();
+    let mut line = String::new();
+    client.read_line(&mut line).unwrap();
+    assert_eq!(line, "END\r\n");
+}
+
+#[test]
+fn gat_and_gats_fetch_and_set_a_new_exptime() {
+    let mut client = start(Config::default());
+    client.send(b"gat 10 missing\r\n");
+    assert_eq!(client.line(), "END");
+    assert_eq!(client.set("shortened", 3, b"value"), "STORED");
+    assert_eq!(client.call("set kept 0 1 5\r\nvalue\r\n"), "STORED");
+
+    client.send(b"gat 1 shortened\r\n");
+    assert_eq!(client.line(), "VALUE shortened 3 5");
+    assert_eq!(client.line(), "value");
+    assert_eq!(client.line(), "END");
+    // gats also hands out the cas, a miss among the keys is left out
+    client.send(b"gats 0 kept missing\r\n");
+    let header = client.line();
+    assert!(header.starts_with("VALUE kept 0 5 "), "bad VALUE line {:?}", header);
+    assert_eq!(client.line(), "value");
+    assert_eq!(client.line(), "END");
+
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(client.get("shortened"), None);
+    assert_eq!(client.get("kept"), Some((0, b"value".to_vec())));
+}
+
+#[test]
+fn pipelined_flood_waits_for_the_reply_to_drain() {
+    let mut client = start(Config { max_write_buffer: 64 * 1024, ..Config::default() });
+    assert_eq!(set_large(&mut client, "big", 256 * 1024), "STORED");
+    let mut flood = client.another();
+    // far more reply than the socket buffers hold, then a set at the very end
+    let gets = 100;
+    let mut request = b"get big\r\n".repeat(gets);
+    request.extend(b"set marker 0 0 1\r\nx\r\n");
+    flood.send(&request);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client.get("marker"), None);
+
+    for _ in 0..gets {
+        assert_eq!(flood.line(), "VALUE big 0 262144");
+        let mut value = vec![0; 256 * 1024 + 2];
+        flood.reader.read_exact(&mut value).unwrap();
+        assert_eq!(flood.line(), "END");
+    }
+    assert_eq!(flood.line(), "STORED");
+    assert_eq!(client.get("marker"), Some((0, b"x".to_vec())));
+}
+
+#[test]
+fn delete_takes_only_a_zero_time() {
+    let mut client = start(Config::default());
+    assert_eq!(client.call("delete missing 0\r\n"), "NOT_FOUND");
+    assert_eq!(client.set("key", 0, b"value"), "STORED");
+    assert_eq!(client.call("delete key 10\r\n"), "CLIENT_ERROR bad command line format. Usage: delete <key> [noreply]");
+    assert_eq!(client.get("key"), Some((0, b"value".to_vec())));
+    assert_eq!(client.call("delete key 0\r\n"), "DELETED");
+    assert_eq!(client.get("key"), None);
+}
+
+#[test]
+fn stats_settings_reports_the_configuration() {
+    let mut client = start(Config { max_connections: 42, max_item_size: 4096, memory_limit: 1 << 20, read_only: true, ..Config::default() });
+    let settings = client.stats("stats settings\r\n");
+    let port = client.addr.port().to_string();
+    for &(name, value) in &[("maxconns", "42"), ("item_size_max", "4096"), ("maxbytes", "1048576"), ("tcpport", port.as_str()),
+                             ("inter", "127.0.0.1"), ("domain_socket", "NULL"), ("read_only", "yes"), ("ssl_enabled", "no")] {
+        assert_eq!(settings.get(name).map(String::as_str), Some(value), "{}", name);
+    }
+}
+
+#[test]
+fn stats_items_and_slabs_report_a_single_class() {
+    let mut client = start(Config::default());
+    assert!(client.stats("stats items\r\n").is_empty());
+    let slabs = client.stats("stats slabs\r\n");
+    assert_eq!((slabs["active_slabs"].as_str(), slabs["total_malloced"].as_str()), ("0", "0"));
+
+    assert_eq!(client.set("a", 0, b"value"), "STORED");
+    assert_eq!(client.set("b", 0, b"other value"), "STORED");
+    let items = client.stats("stats items\r\n");
+    assert_eq!((items["items:1:number"].as_str(), items["items:1:evicted"].as_str()), ("2", "0"));
+    assert!(items.contains_key("items:1:age"));
+
+    let bytes = client.stats("stats\r\n")["bytes"].clone();
+    let slabs = client.stats("stats slabs\r\n");
+    assert_eq!(slabs["active_slabs"], "1");
+    assert_eq!(slabs["1:mem_requested"], bytes);
+    assert_eq!(slabs["total_malloced"], bytes);
+    assert_eq!(slabs["1:cmd_set"], "2");
+    assert!(!slabs.keys().any(|name| name.contains("chunk") || name.contains("pages")), "made up fields in {:?}", slabs);
+}
+
+// The stats conns counters of the given client, by name without the prefix
+fn conn_stats(conns: &HashMap<String, String>, client: &Client) -> HashMap<String, String> {
+    let addr = format!("tcp:{}", client.reader.get_ref().local_addr().unwrap());
+    let name = conns.iter().find(|stat| stat.0.ends_with(":addr") && *stat.1 == addr).map(|stat| stat.0.clone())
+        .unwrap_or_else(|| panic!("{} missing from {:?}", addr, conns));
+    let prefix = &name[..name.len() - "addr".len()];
+    conns.iter()
+        .filter_map(|(name, value)| name.strip_prefix(prefix).map(|name| (name.to_string(), value.clone())))
+        .collect()
+}
+
+#[test]
+fn stats_conns_counts_each_connections_traffic() {
+    let mut client = start(Config::default());
+    let mut other = client.another();
+    assert_eq!(client.set("a", 0, b"value"), "STORED");
+    assert_eq!(client.get("a"), Some((0, b"value".to_vec())));
+    let conns = other.stats("stats conns\r\n");
+
+    let stats = conn_stats(&conns, &client);
+    // set a 0 0 5, its data block and get a
+    assert_eq!(stats["bytes_read"], (20 + 7).to_string());
+    // STORED, and VALUE a 0 5, value and END
+    assert_eq!(stats["bytes_written"], (8 + 25).to_string());
+    assert_eq!(stats["commands"], "2");
+    // the one asking is listed as well, with its own command counted
+    assert_eq!(conn_stats(&conns, &other)["commands"], "1");
+}
+
+#[test]
+fn stats_conns_lists_the_clients_of_every_loop() {
+    let first = start(Config { threads: 2, ..Config::default() });
+    let mut clients: Vec<Client> = (0..3).map(|_| first.another()).collect();
+    clients.push(first);
+    for (i, client) in clients.iter_mut().enumerate() {
+        assert_eq!(client.set(&format!("key{}", i), 0, b"value"), "STORED");
+    }
+    let conns = clients[0].stats("stats conns\r\n");
+    for client in &clients {
+        assert!(conn_stats(&conns, client)["commands"].parse::<u64>().unwrap() >= 1);
+    }
+    // clients are handed to the loops in turn, and named after theirs
+    let loops: HashSet<&str> = conns.keys()
+        .filter(|name| name.ends_with(":addr"))
+        .map(|name| name.split(':').next().unwrap())
+        .collect();
+    assert_eq!(loops.len(), 2, "{:?}", conns);
+}
+
+impl Client {
+    // A watch event, which unlike replies ends in a bare newline
+    fn event(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with('\n'), "unterminated event {:?}", line);
+        line.trim_end().to_string()
+    }
+}
+
+// The gid= field of a watch event
+fn event_gid(event: &str) -> u64 {
+    event.split(' ').find_map(|field| field.strip_prefix("gid=")).unwrap().parse().unwrap()
+}
+
+#[test]
+fn watcher_sees_other_clients_fetches_until_it_disconnects() {
+    let mut client = start(Config::default());
+    let mut watcher = client.another();
+    assert_eq!(watcher.call("watch fetchers\r\n"), "OK");
+    assert_eq!(client.set("key", 0, b"value"), "STORED");
+    client.get("key");
+    client.get("missing");
+    let hit = watcher.event();
+    assert!(hit.contains("type=item_get key=key status=found size=5"), "{}", hit);
+    let miss = watcher.event();
+    assert!(miss.contains("key=missing status=not_found"), "{}", miss);
+    assert_eq!(event_gid(&miss), event_gid(&hit) + 1);
+
+    // with nobody watching, fetches are no longer turned into events
+    drop(watcher);
+    thread::sleep(Duration::from_millis(100));
+    client.get("key");
+    let mut watcher = client.another();
+    assert_eq!(watcher.call("watch fetchers\r\n"), "OK");
+    client.get("key");
+    assert_eq!(event_gid(&watcher.event()), event_gid(&miss) + 1);
+}
+
+#[test]
+fn set_and_get_over_ipv6() {
+    let addr = match TcpListener::bind("[::1]:0").and_then(|listener| listener.local_addr()) {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("skipping, no ipv6 loopback: {}", e);
+            return;
+        }
+    };
+    thread::spawn(move || remcached::serve(Config { bind: Address::Tcp(addr), ..Config::default() }).expect("server failed"));
+    let mut client = Client::connect(addr);
+    assert_eq!(client.set("key", 0, b"value"), "STORED");
+    assert_eq!(client.get("key"), Some((0, b"value".to_vec())));
+}
+
+#[test]
+fn commands_past_the_rate_limit_are_refused() {
+    let mut client = start(Config { rate_limit: 5, ..Config::default() });
+    client.send(&b"get key\r\n".repeat(8));
+    let replies: Vec<String> = (0..8).map(|_| client.line()).collect();
+    assert_eq!(replies.iter().filter(|reply| *reply == "END").count(), 5, "{:?}", replies);
+    assert_eq!(replies.iter().filter(|reply| *reply == "SERVER_ERROR rate limited").count(), 3, "{:?}", replies);
+    // other clients have buckets of their own
+    let mut other = client.another();
+    assert_eq!(other.get("key"), None);
+    // and a quarter of a second refills a token or two
+    thread::sleep(Duration::from_millis(250));
+    assert_eq!(client.get("key"), None);
+}
+
+#[test]
+fn bytes_read_and_written_count_all_traffic() {
+    let mut client = start(Config::default());
+    let before = client.stats("stats\r\n");
+    assert_eq!(client.set("key", 0, b"value"), "STORED");
+    let after = client.stats("stats\r\n");
+
+    let counter = |stats: &HashMap<String, String>, name: &str| stats[name].parse::<u64>().unwrap();
+    // the first stats reply went out after its own counters were taken
+    let stats_reply: usize = before.iter().map(|(name, value)| "STAT  \r\n".len() + name.len() + value.len()).sum::<usize>() + "END\r\n".len();
+    // set key 0 0 5 and its data block, then the second stats
+    assert_eq!(counter(&after, "bytes_read") - counter(&before, "bytes_read"), 22 + 7);
+    assert_eq!(counter(&after, "bytes_written") - counter(&before, "bytes_written"), stats_reply as u64 + 8);
+}
+
+#[test]
+fn stats_reset_zeroes_the_counters_and_keeps_the_items() {
+    let mut client = start(Config::default());
+    assert_eq!(client.set("a", 0, b"value"), "STORED");
+    assert_eq!(client.set("b", 0, b"other"), "STORED");
+    client.get("a");
+    client.get("missing");
+    let before = client.stats("stats\r\n");
+    assert_eq!((before["cmd_get"].as_str(), before["get_hits"].as_str()), ("2", "1"));
+
+    assert_eq!(client.call("stats reset\r\n"), "RESET");
+    let after = client.stats("stats\r\n");
+    for name in &["cmd_get", "cmd_set", "get_hits", "get_misses", "total_connections", "rejected_connections"] {
+        assert_eq!(after[*name], "0", "{}", name);
+    }
+    // only this stats command came in and only RESET went out since
+    assert_eq!((after["bytes_read"].as_str(), after["bytes_written"].as_str()), ("7", "7"));
+    for name in &["curr_items", "bytes"] {
+        assert_eq!(after[*name], before[*name], "{}", name);
+    }
+}
+
+#[test]
+fn values_past_the_compress_threshold_take_less_memory() {
+    let value = b"0123456789abcdef".repeat(63)[..1000].to_vec();
+    let mut plain = start(Config::default());
+    let mut compressing = start(Config { compress_threshold: 512, ..Config::default() });
+    for client in &mut [&mut plain, &mut compressing] {
+        assert_eq!(client.set("key", 3, &value), "STORED");
+    }
+    let bytes = |client: &mut Client| client.stats("stats\r\n")["bytes"].parse::<usize>().unwrap();
+    let (plain_bytes, compressed_bytes) = (bytes(&mut plain), bytes(&mut compressing));
+    assert!(compressed_bytes + 500 < plain_bytes, "{} bytes compressed, {} plain", compressed_bytes, plain_bytes);
+    assert_eq!(compressing.get("key"), Some((3, value)));
+}
+
+#[test]
+fn meta_set_is_refused_without_buffering_the_value() {
+    let mut client = start(Config { read_only: true, max_read_buffer: 4096, ..Config::default() });
+    client.send(b"ms big 1048576\r\n");
+    send_filler(&mut client, 1 << 20);
+    assert_eq!(client.call("\r\nmn\r\n"), "SERVER_ERROR read-only");
+    assert_eq!(client.line(), "MN");
+
+    let mut client = start(Config { memory_limit: 1 << 20, max_item_size: 4 << 20, max_read_buffer: 4096, ..Config::default() });
+    client.send(b"ms big 2097152 T0\r\n");
+    send_filler(&mut client, 2 << 20);
+    assert_eq!(client.call("\r\nmn\r\n"), "SERVER_ERROR out of memory storing object");
+    assert_eq!(client.line(), "MN");
+    assert_eq!(client.call("ms small 1\r\nx\r\n"), "HD");
+}
+
+#[test]
+fn binary_set_is_refused_without_buffering_the_value() {
+    let extras = [0; 8];
+    let mut client = start(Config { read_only: true, max_read_buffer: 4096, ..Config::default() });
+    let mut header = binary_request(0x01, 51, 0, &extras, b"big", &[]);
+    header[8..12].copy_from_slice(&((extras.len() + 3 + (1 << 20)) as u32).to_be_bytes());
+    client.send(&header);
+    send_filler(&mut client, 1 << 20);
+    let refused = client.binary_reply();
+    assert_eq!((refused.status, refused.opaque), (0x0083, 51));
+    client.send(&binary_request(0x00, 52, 0, &[], b"big", &[]));
+    assert_eq!(client.binary_reply().status, 0x0001);
+
+    let mut client = start(Config { memory_limit: 1 << 20, max_item_size: 4 << 20, max_read_buffer: 4096, ..Config::default() });
+    header[8..12].copy_from_slice(&((extras.len() + 3 + (2 << 20)) as u32).to_be_bytes());
+    client.send(&header);
+    send_filler(&mut client, 2 << 20);
+    let refused = client.binary_reply();
+    assert_eq!((refused.status, refused.opaque), (0x0082, 51));
+    client.send(&binary_request(0x01, 53, 0, &extras, b"small", b"x"));
+    assert_eq!(client.binary_reply().status, 0x0000);
+}
@@ -0,0 +1,137 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The binary on a socket of its own, keeping the cache in the given snapshot
+struct Server {
+    process: Child,
+    socket: PathBuf,
+    client: BufReader<UnixStream>,
+}
+
+impl Server {
+    fn start(name: &str, snapshot: &Path) -> Server {
+        let socket = env::temp_dir().join(format!("remcached-snapshot-{}-{}.sock", name, process::id()));
+        let _ = fs::remove_file(&socket);
+        let process = Command::new(env!("CARGO_BIN_EXE_remcached"))
+            .arg("--bind").arg(&socket).arg("--snapshot").arg(snapshot)
+            .env_remove("RUST_LOG")
+            .stderr(Stdio::piped())
+            .spawn().unwrap();
+
+        let started = Instant::now();
+        let connected = loop {
+            match UnixStream::connect(&socket) {
+                Ok(connected) => break connected,
+                Err(e) if started.elapsed() > Duration::from_secs(5) => panic!("server never came up: {}", e),
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        connected.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        Server { process, socket, client: BufReader::new(connected) }
+    }
+
+    // Sends a request and reads back lines up to the last one given
+    fn call(&mut self, request: &str, last: &str) -> Vec<String> {
+        self.client.get_mut().write_all(request.as_bytes()).unwrap();
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            self.client.read_line(&mut line).unwrap();
+            assert!(line.ends_with("\r\n"), "unterminated reply {:?}", line);
+            line.truncate(line.len() - 2);
+            let done = line == last;
+            lines.push(line);
+            if done {
+                return lines;
+            }
+        }
+    }
+
+    // Shuts down the way SIGTERM does, which saves the snapshot, and hands
+    // back everything logged
+    fn stop(mut self) -> String {
+        Command::new("kill").arg(self.process.id().to_string()).status().unwrap();
+        let mut log = String::new();
+        self.process.stderr.take().unwrap().read_to_string(&mut log).unwrap();
+        let status = self.process.wait().unwrap();
+        let _ = fs::remove_file(&self.socket);
+        assert!(status.success(), "exited with {}: {}", status, log);
+        log
+    }
+}
+
+fn snapshot_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("remcached-snapshot-{}-{}.snap", name, process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+// A snapshot written by hand, in the format snapshot.rs documents
+fn snapshot_item(key: &[u8], flags: u32, exptime: i64, cas: u64, value: &[u8]) -> Vec<u8> {
+    let mut item = (key.len() as u32).to_be_bytes().to_vec();
+    item.extend(key);
+    item.extend(&flags.to_be_bytes());
+    item.extend(&exptime.to_be_bytes());
+    item.extend(&cas.to_be_bytes());
+    item.extend(&(value.len() as u32).to_be_bytes());
+    item.extend(value);
+    item
+}
+
+#[test]
+fn items_survive_a_restart() {
+    let path = snapshot_path("restart");
+    let mut server = Server::start("restart", &path);
+    assert_eq!(server.call("set key 5 0 5\r\nvalue\r\n", "STORED"), ["STORED"]);
+    let before = server.call("gets key\r\n", "END");
+    server.stop();
+    assert!(path.exists());
+
+    let mut server = Server::start("restart", &path);
+    assert_eq!(server.call("gets key\r\n", "END"), before);
+    // cas values carry on from the highest one loaded
+    let cas: u64 = before[0].rsplit(' ').next().unwrap().parse().unwrap();
+    assert_eq!(server.call("set other 0 0 1\r\nx\r\n", "STORED"), ["STORED"]);
+    let other = server.call("gets other\r\n", "END");
+    assert!(other[0].rsplit(' ').next().unwrap().parse::<u64>().unwrap() > cas, "{:?}", other);
+    server.stop();
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn items_expired_by_load_time_are_dropped() {
+    let path = snapshot_path("expired");
+    let mut snapshot = b"RMCSNAP1".to_vec();
+    snapshot.extend(snapshot_item(b"expired", 0, 1000, 1, b"gone"));
+    snapshot.extend(snapshot_item(b"kept", 0, 0, 2, b"value"));
+    fs::write(&path, snapshot).unwrap();
+
+    let mut server = Server::start("expired", &path);
+    assert_eq!(server.call("get expired\r\n", "END"), ["END"]);
+    assert_eq!(server.call("get kept\r\n", "END"), ["VALUE kept 0 5", "value", "END"]);
+    server.stop();
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn bad_snapshots_are_reported_and_the_server_starts_anyway() {
+    let mut truncated = b"RMCSNAP1".to_vec();
+    truncated.extend(snapshot_item(b"key", 0, 0, 1, b"value"));
+    truncated.truncate(truncated.len() - 2);
+    for &(name, ref contents) in &[("garbage", b"not a snapshot at all".to_vec()), ("truncated", truncated)] {
+        let path = snapshot_path(name);
+        fs::write(&path, contents).unwrap();
+        let mut server = Server::start(name, &path);
+        assert_eq!(server.call("set key 0 0 1\r\nx\r\n", "STORED"), ["STORED"]);
+        let log = server.stop();
+        assert!(log.contains("failed to load snapshot"), "{}: {}", name, log);
+        assert!(!log.contains("panicked"), "{}: {}", name, log);
+        let _ = fs::remove_file(&path);
+    }
+}